@@ -0,0 +1,83 @@
+use std::env;
+use std::fs;
+use std::process::exit;
+use std::time::Instant;
+
+use iceburn::tune::{error, load_dataset, Tuner};
+use iceburn::weight::{params, params_source};
+
+const USAGE: &str = "usage: tune <dataset> [--epochs N] [--lr RATE] [--out FILE]";
+
+/// Texel tuning of the hand-crafted evaluation on a file of `<fen> <result>` lines,
+/// writes the tuned constants of weight.rs as Rust source
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
+        exit(1);
+    }
+
+    let dataset = &args[0];
+    let mut epochs = 1000;
+    let mut learning_rate = 1.;
+    let mut out = "tuned.rs".to_string();
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1).unwrap_or_else(|| {
+            eprintln!("{}", USAGE);
+            exit(1)
+        });
+        match args[i].as_str() {
+            "--epochs" => epochs = value.parse().expect("--epochs expects an integer"),
+            "--lr" => learning_rate = value.parse().expect("--lr expects a number"),
+            "--out" => out = value.clone(),
+            _ => {
+                eprintln!("{}", USAGE);
+                exit(1);
+            }
+        }
+        i += 2;
+    }
+
+    let start = Instant::now();
+    let (samples, skipped) = load_dataset(dataset).unwrap_or_else(|e| {
+        eprintln!("cannot read {}: {}", dataset, e);
+        exit(1)
+    });
+    if samples.is_empty() {
+        eprintln!("no positions in {}", dataset);
+        exit(1);
+    }
+    println!(
+        "loaded {} positions, skipped {} lines in {:?}",
+        samples.len(),
+        skipped,
+        start.elapsed()
+    );
+
+    let mut tuner = Tuner::new(&params(), &samples, learning_rate);
+    println!("k {:.4}", tuner.k);
+
+    for epoch in 0..epochs {
+        let e = tuner.step(&samples);
+        if epoch % 50 == 0 {
+            println!("epoch {} error {:.8}", epoch, e);
+            fs::write(&out, params_source(&tuner.rounded())).unwrap();
+        }
+    }
+
+    let tuned = tuner.rounded();
+    println!(
+        "final error {:.8}",
+        error(
+            &tuned
+                .iter()
+                .map(|x| (x.0 as f64, x.1 as f64))
+                .collect::<Vec<_>>(),
+            &samples,
+            tuner.k
+        )
+    );
+    fs::write(&out, params_source(&tuned)).unwrap();
+    println!("wrote {}", out);
+}
@@ -0,0 +1,19 @@
+pub mod book;
+pub mod chess;
+pub mod nnue;
+pub mod options;
+pub mod ordering;
+pub mod perft;
+pub mod pruning;
+pub mod report;
+pub mod search;
+pub mod see;
+pub mod smp;
+pub mod syzygy;
+pub mod time;
+pub mod timeman;
+pub mod tt;
+pub mod tune;
+pub mod utils;
+pub mod weight;
+pub mod zobrist;
@@ -1,33 +1,24 @@
 #![feature(core_intrinsics)]
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 use std::io;
 use std::process::exit;
 use std::str::FromStr;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::chess::fen::Fen;
-use crate::chess::uci::Uci;
-use crate::chess::{uci, CastlingMode, Chess, Color, FromSetup, Position, Setup};
-use crate::nnue::NNUE_FILE;
-use crate::search::Search;
-use crate::time::calc_time;
-use crate::timeman::{TimeControl, Timer};
-use crate::tt::TranspositionTable;
-
-pub mod chess;
-pub mod nnue;
-pub mod ordering;
-pub mod perft;
-pub mod pruning;
-pub mod search;
-pub mod time;
-pub mod timeman;
-pub mod tt;
-pub mod utils;
-pub mod weight;
+use iceburn::book::Book;
+use iceburn::chess::fen::Fen;
+use iceburn::chess::uci::Uci;
+use iceburn::chess::{uci, CastlingMode, Chess, FromSetup, Position};
+use iceburn::nnue::NNUE_FILE;
+use iceburn::options::{parse_setoption, EngineOptions, OPTIONS};
+use iceburn::smp::{lazy_smp, SEARCH_STACK_SIZE};
+use iceburn::timeman::{parse_clock, parse_searchmoves, TimeControl};
+use iceburn::tt::TranspositionTable;
+use iceburn::weight::uci_score;
+use iceburn::{nnue, perft, report, syzygy, zobrist};
 
 fn read_line() -> String {
     let mut line = String::new();
@@ -35,14 +26,45 @@ fn read_line() -> String {
     line
 }
 
+/// Raises the stop flag and waits for the running search to print its bestmove
+fn stop_search_thread(
+    search_thread: &mut Option<thread::JoinHandle<()>>,
+    stop: &AtomicBool,
+    ponder: &AtomicBool,
+) {
+    if let Some(handle) = search_thread.take() {
+        ponder.store(false, Ordering::Relaxed);
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+        stop.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Varies from one call to the next, for the book choices
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos() as u64)
+}
+
 fn uci() {
     let mut board = Chess::default();
     let mut move_table = Vec::with_capacity(100);
-    let mut tt = TranspositionTable::default();
+    let mut options = EngineOptions::default();
+    let mut tt = Arc::new(TranspositionTable::with_size(options.hash));
     let stop_search = Arc::new(AtomicBool::new(false));
+    let ponder = Arc::new(AtomicBool::new(false));
+    let mut search_thread: Option<thread::JoinHandle<()>> = None;
+    let mut book: Option<Book> = None;
 
     loop {
         let line = read_line();
+        if line.is_empty() {
+            // stdin was closed
+            stop_search_thread(&mut search_thread, &stop_search, &ponder);
+            exit(0);
+        }
+
         let cmd_slice = line.trim();
         let (token, args) = if let Some(idx) = cmd_slice.find(char::is_whitespace) {
             cmd_slice.split_at(idx)
@@ -52,56 +74,109 @@ fn uci() {
 
         let args = args.trim();
 
+        // only the commands that change what is searched or the hash table stop the search,
+        // the others are answered while it keeps running
+        if matches!(
+            token,
+            "stop" | "go" | "position" | "ucinewgame" | "quit" | "savehash" | "loadhash"
+        ) {
+            stop_search_thread(&mut search_thread, &stop_search, &ponder);
+        }
+
         match token {
             "quit" => {
                 exit(0);
             }
             "stop" => {}
+            "ponderhit" => ponder.store(false, Ordering::Relaxed),
             "isready" => println!("readyok"),
             "ucinewgame" => {
                 board = Chess::default();
-                tt = TranspositionTable::default();
+                tt = Arc::new(TranspositionTable::with_size(options.hash));
             }
             "uci" => {
                 println!("id name Iceburn 2.0 DEV Nov15");
                 println!("id author SnowballSH");
+                for option in OPTIONS {
+                    println!("{}", option);
+                }
                 println!("uciok");
             }
+            "setoption" => {
+                let (name, value) = match parse_setoption(args) {
+                    Some(x) => x,
+                    None => {
+                        println!("info string Usage: setoption name <id> [value <x>]");
+                        continue;
+                    }
+                };
+                match options.set(name, value) {
+                    Ok(option) => match option.name {
+                        "Hash" => tt = Arc::new(TranspositionTable::with_size(options.hash)),
+                        "Clear Hash" => tt.clear(),
+                        "BookFile" if options.book_file.is_empty() => book = None,
+                        "BookFile" => match Book::load(&options.book_file) {
+                            Ok(x) => {
+                                println!("info string {} book entries", x.len());
+                                book = Some(x);
+                            }
+                            Err(x) => {
+                                println!("info string {}: {}", options.book_file, x);
+                                book = None;
+                            }
+                        },
+                        "SyzygyPath" => match syzygy::syzygy_init(&options.syzygy_path) {
+                            Ok(count) => println!("info string found {} tablebases", count),
+                            Err(x) => println!("info string {}: {}", options.syzygy_path, x),
+                        },
+                        _ => {}
+                    },
+                    Err(x) => println!("info string {}", x),
+                }
+            }
+            "savehash" => match tt.save(args) {
+                Ok(()) => println!("info string saved hash to {}", args),
+                Err(x) => println!("info string {}: {}", args, x),
+            },
+            "loadhash" => match tt.load(args) {
+                Ok(()) => println!("info string loaded hash from {}", args),
+                Err(x) => println!("info string {}: {}", args, x),
+            },
             "position" => {
+                let idx = args.find("moves");
+
                 if args.starts_with("fen") {
-                    let fenpart = &args[4..];
+                    let fenpart = match idx {
+                        Some(x) => &args[4..x],
+                        None => &args[4..],
+                    };
                     board = Chess::from_setup(
-                        &Fen::from_str(fenpart).unwrap_or_else(|x| panic!("{}", x)),
+                        &Fen::from_str(fenpart.trim()).unwrap_or_else(|x| panic!("{}", x)),
                         CastlingMode::Standard,
                     )
                     .unwrap();
-                    move_table.clear();
-                    continue;
-                }
-
-                if args.starts_with("startpos") {
-                    move_table.clear();
+                } else if args.starts_with("startpos") {
                     board = Chess::default();
-                    if args == "startpos" {
-                        continue;
-                    }
                 }
 
-                let idx = args.find("moves");
+                let mut hash = zobrist::hash(&board);
+                move_table.clear();
+                move_table.push(hash);
 
                 let moves = if let Some(x) = idx {
                     let p = &args[x..];
-                    p.split(" ").collect::<Vec<&str>>()[1..].to_vec()
+                    p.split_whitespace().collect::<Vec<&str>>()[1..].to_vec()
                 } else {
                     vec![]
                 };
 
                 for m in moves {
-                    board.play_unchecked(&uci::Uci::from_str(m).unwrap().to_move(&board).unwrap());
-                    let mut hasher = DefaultHasher::new();
-                    board.board().hash(&mut hasher);
-                    let hs = hasher.finish();
-                    move_table.push(hs);
+                    let m = uci::Uci::from_str(m).unwrap().to_move(&board).unwrap();
+                    let mut nb = board.clone();
+                    nb.play_unchecked(&m);
+                    hash = zobrist::update(hash, &board, &m, &nb);
+                    board = nb;
+                    move_table.push(hash);
                 }
             }
             "go" => {
@@ -111,73 +186,83 @@ fn uci() {
                     continue;
                 }
 
-                let mut arg_slice: Vec<&str> = args.split(" ").collect();
-                while arg_slice.len() < 10 {
-                    arg_slice.push("");
+                // every go gets a bestmove, bad searchmoves are only reported
+                let (search_moves, errors) = parse_searchmoves(args, &board);
+                for x in errors {
+                    println!("info string {}", x);
                 }
+                let time_control = TimeControl::from(args).with_overhead(options.move_overhead);
+                let clock = parse_clock(args).map(|x| x.with_overhead(options.move_overhead));
+                let pondering = args.split_whitespace().any(|x| x == "ponder");
 
-                let time_control: TimeControl;
-
-                if arg_slice[0] == "movetime" {
-                    time_control = TimeControl::FixedMillis(arg_slice[1].parse().unwrap());
-                } else if arg_slice[0] == "wtime" && arg_slice[2] == "btime" {
-                    let wtime: usize = arg_slice[1].parse().unwrap();
-                    let btime: usize = arg_slice[3].parse().unwrap();
-                    let mut winc: usize = 0;
-                    let mut binc: usize = 0;
-                    let length = board.fullmoves().get() as f64;
-                    let expected_game_length: f64 = 50.0;
-                    let mut moves_to_go: f64 = 20f64.max(expected_game_length - length);
-                    if arg_slice[4] == "winc" && arg_slice[6] == "binc" {
-                        winc = arg_slice[5].parse().unwrap();
-                        binc = arg_slice[7].parse().unwrap();
-                        if arg_slice[8] == "movestogo" {
-                            moves_to_go = arg_slice[9].parse().unwrap();
-                        }
-                    } else {
-                        if arg_slice[4] == "movestogo" {
-                            moves_to_go = arg_slice[5].parse().unwrap();
-                        }
+                // book moves are played at once, but not while analysing
+                if options.own_book
+                    && !pondering
+                    && search_moves.is_empty()
+                    && time_control != TimeControl::Infinite
+                {
+                    let book_move = book
+                        .as_ref()
+                        .and_then(|x| x.pick(&board, options.book_strategy, time_seed()));
+                    if let Some(m) = book_move {
+                        println!("bestmove {}", Uci::from_standard(&m));
+                        continue;
                     }
+                }
 
-                    let left = if board.turn() == Color::White {
-                        wtime as f64
-                    } else {
-                        btime as f64
-                    };
+                ponder.store(pondering, Ordering::Relaxed);
 
-                    let our_time = calc_time(
-                        moves_to_go,
-                        left,
-                        if board.turn() == Color::White {
-                            winc as f64
-                        } else {
-                            binc as f64
-                        },
-                    );
-
-                    time_control = TimeControl::FixedMillis(
-                        our_time
-                            .max(1.0)
-                            // must be in time
-                            .min(left - 1.0) as u64,
-                    );
-                } else {
-                    time_control = TimeControl::FixedMillis(2000);
-                }
+                let board = board.clone();
+                let stop = stop_search.clone();
+                let ponder = ponder.clone();
+                let tt = tt.clone();
+                let move_table = move_table.clone();
+                let options = options.clone();
+                stop.store(false, Ordering::Relaxed);
+                search_thread = Some(
+                    thread::Builder::new()
+                        .name("search".to_string())
+                        .stack_size(SEARCH_STACK_SIZE)
+                        .spawn(move || {
+                            let (best_move, best_score, ponder_move) = lazy_smp(
+                                &board,
+                                time_control,
+                                clock,
+                                stop,
+                                tt,
+                                &move_table,
+                                &search_moves,
+                                &options,
+                                if pondering {
+                                    Some(ponder.clone())
+                                } else {
+                                    None
+                                },
+                            );
 
-                let mut searcher = Search::new(
-                    Timer::new(&board, time_control, stop_search.clone()),
-                    &mut tt,
+                            // bestmove may only be sent after ponderhit or stop
+                            while ponder.load(Ordering::Relaxed) {
+                                thread::sleep(Duration::from_millis(1));
+                            }
+
+                            println!("info score {}", uci_score(best_score));
+                            match ponder_move {
+                                Some(ponder_move) => println!(
+                                    "bestmove {} ponder {}",
+                                    Uci::from_standard(&best_move),
+                                    Uci::from_standard(&ponder_move)
+                                ),
+                                None => println!("bestmove {}", Uci::from_standard(&best_move)),
+                            }
+                        })
+                        .unwrap(),
                 );
-                searcher.move_table = move_table.clone();
-                let res = searcher.mtdf(&mut board);
-                let best_move = res.0;
-                let best_score = res.1;
-                println!("info score cp {}", best_score);
-                println!("bestmove {}", Uci::from_standard(&best_move));
-                tt.clear();
             }
+            "d" => println!("{}", report::position_report(&board)),
+            "eval" => println!(
+                "{}",
+                report::eval_report(&board, nnue::network().as_deref())
+            ),
             _ => {
                 println!("No such command")
             }
@@ -187,6 +272,8 @@ fn uci() {
 
 fn main() {
     // init nnue
-    nnue::nnue_init(NNUE_FILE);
+    if let Err(x) = nnue::nnue_init(NNUE_FILE) {
+        println!("info string {}: {}", NNUE_FILE, x);
+    }
     uci();
 }
@@ -1,30 +1,279 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
 use lazy_static::lazy_static;
-use libloading;
 
-use crate::chess::{Chess, Color, Setup};
+use crate::chess::fen::Fen;
+use crate::chess::{CastlingMode, Chess, Color, FromSetup, Move, Role, Setup};
+
+// HalfKP(Friend)[41024->256x2]-32-32-1, the Stockfish 12 architecture
+// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md
+pub const NNUE_VERSION: u32 = 0x7AF32F16;
+pub const HALF_DIMENSIONS: usize = 256;
+pub const FT_INPUTS: usize = 64 * PS_END;
+const L1_SIZE: usize = 32;
+const L2_SIZE: usize = 32;
+const FV_SCALE: i32 = 16;
+const WEIGHT_SCALE_BITS: i32 = 6;
+
+// piece-square offsets, from the point of view of the perspective
+const PS_W_PAWN: usize = 1;
+const PS_B_PAWN: usize = 64 + 1;
+const PS_W_KNIGHT: usize = 2 * 64 + 1;
+const PS_B_KNIGHT: usize = 3 * 64 + 1;
+const PS_W_BISHOP: usize = 4 * 64 + 1;
+const PS_B_BISHOP: usize = 5 * 64 + 1;
+const PS_W_ROOK: usize = 6 * 64 + 1;
+const PS_B_ROOK: usize = 7 * 64 + 1;
+const PS_W_QUEEN: usize = 8 * 64 + 1;
+const PS_B_QUEEN: usize = 9 * 64 + 1;
+const PS_END: usize = 10 * 64 + 1;
+
+/// Indexed by [perspective][piece], piece encoded as in `decode_board`
+#[rustfmt::skip]
+const PIECE_TO_INDEX: [[usize; 13]; 2] = [
+    [0, 0, PS_W_QUEEN, PS_W_ROOK, PS_W_BISHOP, PS_W_KNIGHT, PS_W_PAWN,
+        0, PS_B_QUEEN, PS_B_ROOK, PS_B_BISHOP, PS_B_KNIGHT, PS_B_PAWN],
+    [0, 0, PS_B_QUEEN, PS_B_ROOK, PS_B_BISHOP, PS_B_KNIGHT, PS_B_PAWN,
+        0, PS_W_QUEEN, PS_W_ROOK, PS_W_BISHOP, PS_W_KNIGHT, PS_W_PAWN],
+];
+
+#[inline(always)]
+fn orient(perspective: usize, sq: usize) -> usize {
+    sq ^ if perspective == 0 { 0 } else { 63 }
+}
+
+/// HalfKP feature index of piece `pc` on `sq` with the perspective's king on `ksq`
+#[inline(always)]
+pub fn make_index(perspective: usize, sq: usize, pc: usize, ksq: usize) -> usize {
+    orient(perspective, sq) + PIECE_TO_INDEX[perspective][pc] + PS_END * orient(perspective, ksq)
+}
+
+#[derive(Clone)]
+pub struct Accumulator {
+    pub values: [[i16; HALF_DIMENSIONS]; 2],
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Accumulator {
+            values: [[0; HALF_DIMENSIONS]; 2],
+        }
+    }
+}
+
+pub struct Network {
+    ft_biases: Vec<i16>,
+    ft_weights: Vec<i16>,
+    l1_biases: Vec<i32>,
+    l1_weights: Vec<i8>,
+    l2_biases: Vec<i32>,
+    l2_weights: Vec<i8>,
+    out_bias: i32,
+    out_weights: Vec<i8>,
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_i16s<R: Read>(r: &mut R, n: usize) -> io::Result<Vec<i16>> {
+    let mut buf = vec![0u8; n * 2];
+    r.read_exact(&mut buf)?;
+    Ok(buf
+        .chunks_exact(2)
+        .map(|x| i16::from_le_bytes([x[0], x[1]]))
+        .collect())
+}
+
+fn read_i32s<R: Read>(r: &mut R, n: usize) -> io::Result<Vec<i32>> {
+    let mut buf = vec![0u8; n * 4];
+    r.read_exact(&mut buf)?;
+    Ok(buf
+        .chunks_exact(4)
+        .map(|x| i32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect())
+}
+
+fn read_i8s<R: Read>(r: &mut R, n: usize) -> io::Result<Vec<i8>> {
+    let mut buf = vec![0u8; n];
+    r.read_exact(&mut buf)?;
+    Ok(buf.into_iter().map(|x| x as i8).collect())
+}
+
+impl Network {
+    pub fn load(eval_file: &str) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(eval_file)?);
+
+        // header
+        let version = read_u32(&mut r)?;
+        if version != NNUE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported NNUE version {:#x}", version),
+            ));
+        }
+        let _hash = read_u32(&mut r)?;
+        let desc_len = read_u32(&mut r)? as usize;
+        let mut desc = vec![0u8; desc_len];
+        r.read_exact(&mut desc)?;
+
+        // feature transformer
+        let _ft_hash = read_u32(&mut r)?;
+        let ft_biases = read_i16s(&mut r, HALF_DIMENSIONS)?;
+        let ft_weights = read_i16s(&mut r, HALF_DIMENSIONS * FT_INPUTS)?;
+
+        // hidden layers
+        let _net_hash = read_u32(&mut r)?;
+        let l1_biases = read_i32s(&mut r, L1_SIZE)?;
+        let l1_weights = read_i8s(&mut r, L1_SIZE * HALF_DIMENSIONS * 2)?;
+        let l2_biases = read_i32s(&mut r, L2_SIZE)?;
+        let l2_weights = read_i8s(&mut r, L2_SIZE * L1_SIZE)?;
+        let out_bias = read_i32s(&mut r, 1)?[0];
+        let out_weights = read_i8s(&mut r, L2_SIZE)?;
+
+        // must be at the end of the file
+        if r.read(&mut [0u8; 1])? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data in NNUE file",
+            ));
+        }
+
+        Ok(Network {
+            ft_biases,
+            ft_weights,
+            l1_biases,
+            l1_weights,
+            l2_biases,
+            l2_weights,
+            out_bias,
+            out_weights,
+        })
+    }
+
+    #[inline]
+    pub fn add_feature(&self, acc: &mut [i16; HALF_DIMENSIONS], index: usize) {
+        let w = &self.ft_weights[index * HALF_DIMENSIONS..(index + 1) * HALF_DIMENSIONS];
+        for (a, w) in acc.iter_mut().zip(w) {
+            *a = a.wrapping_add(*w);
+        }
+    }
+
+    #[inline]
+    pub fn remove_feature(&self, acc: &mut [i16; HALF_DIMENSIONS], index: usize) {
+        let w = &self.ft_weights[index * HALF_DIMENSIONS..(index + 1) * HALF_DIMENSIONS];
+        for (a, w) in acc.iter_mut().zip(w) {
+            *a = a.wrapping_sub(*w);
+        }
+    }
+
+    /// Computes the accumulator from scratch, arrays as produced by `decode_board`
+    pub fn refresh(&self, acc: &mut Accumulator, pieces: &[i32], squares: &[i32]) {
+        for perspective in 0..2 {
+            self.refresh_perspective(acc, perspective, pieces, squares);
+        }
+    }
+
+    pub fn refresh_perspective(
+        &self,
+        acc: &mut Accumulator,
+        perspective: usize,
+        pieces: &[i32],
+        squares: &[i32],
+    ) {
+        let ksq = squares[perspective] as usize;
+        let values = &mut acc.values[perspective];
+        values.copy_from_slice(&self.ft_biases);
+        for i in 2.. {
+            if pieces[i] == 0 {
+                break;
+            }
+            let index = make_index(perspective, squares[i] as usize, pieces[i] as usize, ksq);
+            self.add_feature(values, index);
+        }
+    }
+
+    /// Runs the dense layers on top of a computed accumulator
+    pub fn forward(&self, acc: &Accumulator, turn: usize) -> i32 {
+        let mut input = [0i32; HALF_DIMENSIONS * 2];
+        for (half, perspective) in [turn, turn ^ 1].iter().enumerate() {
+            for i in 0..HALF_DIMENSIONS {
+                input[half * HALF_DIMENSIONS + i] =
+                    (acc.values[*perspective][i] as i32).max(0).min(127);
+            }
+        }
+
+        let mut l1 = [0i32; L1_SIZE];
+        for i in 0..L1_SIZE {
+            let w = &self.l1_weights[i * HALF_DIMENSIONS * 2..(i + 1) * HALF_DIMENSIONS * 2];
+            let sum = self.l1_biases[i]
+                + w.iter()
+                    .zip(input.iter())
+                    .map(|(&w, &x)| w as i32 * x)
+                    .sum::<i32>();
+            l1[i] = (sum >> WEIGHT_SCALE_BITS).max(0).min(127);
+        }
+
+        let mut l2 = [0i32; L2_SIZE];
+        for i in 0..L2_SIZE {
+            let w = &self.l2_weights[i * L1_SIZE..(i + 1) * L1_SIZE];
+            let sum = self.l2_biases[i]
+                + w.iter()
+                    .zip(l1.iter())
+                    .map(|(&w, &x)| w as i32 * x)
+                    .sum::<i32>();
+            l2[i] = (sum >> WEIGHT_SCALE_BITS).max(0).min(127);
+        }
+
+        let out = self.out_bias
+            + self
+                .out_weights
+                .iter()
+                .zip(l2.iter())
+                .map(|(&w, &x)| w as i32 * x)
+                .sum::<i32>();
+
+        out / FV_SCALE
+    }
+
+    /// Same contract as `nnue_evaluate` of the C probe: 0 terminated arrays, kings first
+    pub fn evaluate(&self, turn: i32, pieces: &[i32], squares: &[i32]) -> i32 {
+        let mut acc = Accumulator::default();
+        self.refresh(&mut acc, pieces, squares);
+        self.forward(&acc, turn as usize)
+    }
+}
 
 lazy_static! {
-    static ref NNUE: libloading::Library = unsafe {
-        libloading::Library::new("./nnue/libnnueprobe.dll").unwrap_or_else(|x| panic!("{}", x))
-    };
-    static ref NNUE_INIT: libloading::Symbol<'static, unsafe extern "C" fn(*const u8)> =
-        unsafe { NNUE.get(b"nnue_init").unwrap() };
-    static ref NNUE_EVAL_FEN: libloading::Symbol<'static, unsafe extern "C" fn(*const u8) -> i32> =
-        unsafe { NNUE.get(b"nnue_evaluate_fen").unwrap() };
-    static ref NNUE_EVAL_NORMAL: libloading::Symbol<'static, unsafe extern "C" fn(i32, *const i32, *const i32) -> i32> =
-        unsafe { NNUE.get(b"nnue_evaluate").unwrap() };
+    static ref NNUE: RwLock<Option<Arc<Network>>> = RwLock::new(None);
 }
 
 #[inline]
-pub fn nnue_init(eval_file: &str) {
-    unsafe {
-        NNUE_INIT(eval_file.as_ptr());
-    }
+pub fn nnue_init(eval_file: &str) -> io::Result<()> {
+    let network = Network::load(eval_file)?;
+    *NNUE.write().unwrap() = Some(Arc::new(network));
+    Ok(())
+}
+
+/// The currently loaded network, if any
+#[inline]
+pub fn network() -> Option<Arc<Network>> {
+    NNUE.read().unwrap().clone()
 }
 
 #[inline]
 pub fn nnue_eval_fen(fen: &str) -> i32 {
-    unsafe { NNUE_EVAL_FEN(fen.as_ptr()) }
+    let board: Chess = Chess::from_setup(
+        &Fen::from_str(fen).unwrap_or_else(|x| panic!("{}", x)),
+        CastlingMode::Standard,
+    )
+    .unwrap();
+    nnue_eval_normal(&board)
 }
 
 #[inline]
@@ -56,12 +305,538 @@ pub fn decode_board(board: &Chess) -> (i32, Vec<i32>, Vec<i32>) {
     (turn, pieces, squares)
 }
 
+/// Factor applied to the raw network output, shrinking as the halfmove clock grows
+#[inline]
+pub fn halfmove_scale(board: &Chess) -> f32 {
+    (100 - board.halfmoves().min(95).max(10)) as f32 / 100.0
+}
+
+/// Scales the raw network output down as the halfmove clock grows
+#[inline]
+pub fn scale_halfmoves(v: i32, board: &Chess) -> i32 {
+    (v as f32 * halfmove_scale(board)) as i32
+}
+
 #[inline]
 pub fn nnue_eval_normal(board: &Chess) -> i32 {
     let (turn, pieces, squares) = decode_board(board);
-    let mut v = unsafe { NNUE_EVAL_NORMAL(turn, pieces.as_ptr(), squares.as_ptr()) };
-    v = (v as f32 * ((100 - board.halfmoves().min(95).max(10)) as f32 / 100.0)) as i32;
-    return v;
+    let v = NNUE
+        .read()
+        .unwrap()
+        .as_ref()
+        .expect("NNUE is not initialized")
+        .evaluate(turn, &pieces, &squares);
+    scale_halfmoves(v, board)
+}
+
+/// Estimated value of every piece but the kings for the side to move, by square:
+/// the raw output of the network minus its output with the piece removed
+pub fn piece_values(network: &Network, board: &Chess) -> Vec<(usize, i32)> {
+    let (turn, pieces, squares) = decode_board(board);
+    let full = network.evaluate(turn, &pieces, &squares);
+    // kings come first and the arrays end with a 0
+    (2..pieces.len() - 1)
+        .map(|i| {
+            let (mut p, mut s) = (pieces.clone(), squares.clone());
+            p.remove(i);
+            s.remove(i);
+            (squares[i] as usize, full - network.evaluate(turn, &p, &s))
+        })
+        .collect()
+}
+
+#[inline(always)]
+fn piece_code(color: Color, role: Role) -> usize {
+    let side = if color == Color::White { 0 } else { 1 };
+    (7 - role as usize) + 6 * side
+}
+
+/// Accumulator stack following the search tree,
+/// updated incrementally as moves are made and unmade
+/// without a network every operation is a no-op
+pub struct NnueState {
+    pub network: Option<Arc<Network>>,
+    pub stack: Vec<Accumulator>,
+}
+
+impl NnueState {
+    pub fn new(network: Option<Arc<Network>>) -> Self {
+        NnueState {
+            network,
+            stack: Vec::with_capacity(128),
+        }
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.network.is_some()
+    }
+
+    /// Clears the stack and computes the root accumulator from scratch
+    pub fn reset(&mut self, board: &Chess) {
+        self.stack.clear();
+        if let Some(network) = self.network.as_ref() {
+            let (_, pieces, squares) = decode_board(board);
+            let mut acc = Accumulator::default();
+            network.refresh(&mut acc, &pieces, &squares);
+            self.stack.push(acc);
+        }
+    }
+
+    /// Pushes the accumulator of `after`, reached by playing `m` on `board`
+    pub fn push(&mut self, board: &Chess, m: &Move, after: &Chess) {
+        let network = match self.network.as_ref() {
+            Some(network) => network,
+            None => return,
+        };
+        let mut acc = self.stack.last().unwrap().clone();
+
+        let us = board.turn();
+        let them = !us;
+        let us_perspective = if us == Color::White { 0 } else { 1 };
+        let mut removed: [(usize, usize); 2] = [(0, 0); 2];
+        let mut added: (usize, usize) = (0, 0);
+        let mut n_removed = 0;
+        let mut king_moved = false;
+
+        match *m {
+            Move::Normal {
+                role,
+                from,
+                capture,
+                to,
+                promotion,
+            } => {
+                if role == Role::King {
+                    king_moved = true;
+                } else {
+                    removed[0] = (from as usize, piece_code(us, role));
+                    added = (to as usize, piece_code(us, promotion.unwrap_or(role)));
+                    n_removed = 1;
+                }
+                if let Some(captured) = capture {
+                    removed[n_removed] = (to as usize, piece_code(them, captured));
+                    n_removed += 1;
+                }
+            }
+            Move::EnPassant { from, to } => {
+                let captured = (to as usize & 7) | (from as usize & 56);
+                removed[0] = (from as usize, piece_code(us, Role::Pawn));
+                removed[1] = (captured, piece_code(them, Role::Pawn));
+                added = (to as usize, piece_code(us, Role::Pawn));
+                n_removed = 2;
+            }
+            Move::Castle { king, rook } => {
+                let rook_to = if rook as usize > king as usize {
+                    (king as usize & 56) + 5
+                } else {
+                    (king as usize & 56) + 3
+                };
+                removed[0] = (rook as usize, piece_code(us, Role::Rook));
+                added = (rook_to, piece_code(us, Role::Rook));
+                n_removed = 1;
+                king_moved = true;
+            }
+            Move::Put { .. } => unreachable!(),
+        }
+
+        let (_, pieces, squares) = if king_moved {
+            decode_board(after)
+        } else {
+            (0, vec![], vec![])
+        };
+
+        for perspective in 0..2 {
+            if king_moved && perspective == us_perspective {
+                network.refresh_perspective(&mut acc, perspective, &pieces, &squares);
+                continue;
+            }
+
+            let ksq = after
+                .board()
+                .king_of(if perspective == 0 {
+                    Color::White
+                } else {
+                    Color::Black
+                })
+                .unwrap() as usize;
+            let values = &mut acc.values[perspective];
+            for &(sq, pc) in removed.iter().take(n_removed) {
+                network.remove_feature(values, make_index(perspective, sq, pc, ksq));
+            }
+            if added.1 != 0 {
+                network.add_feature(values, make_index(perspective, added.0, added.1, ksq));
+            }
+        }
+
+        self.stack.push(acc);
+
+        #[cfg(debug_assertions)]
+        self.verify(after);
+    }
+
+    #[inline]
+    pub fn pop(&mut self) {
+        self.stack.pop();
+    }
+
+    /// Evaluates the position on top of the stack, which must be `board`
+    #[inline]
+    pub fn evaluate(&self, board: &Chess) -> i32 {
+        let turn = if board.turn() == Color::White { 0 } else { 1 };
+        let network = self.network.as_ref().expect("NNUE is not initialized");
+        let v = network.forward(self.stack.last().unwrap(), turn);
+        scale_halfmoves(v, board)
+    }
+
+    /// Checks the incremental accumulator against a full refresh
+    #[cfg(debug_assertions)]
+    fn verify(&self, board: &Chess) {
+        let (_, pieces, squares) = decode_board(board);
+        let mut acc = Accumulator::default();
+        self.network
+            .as_ref()
+            .unwrap()
+            .refresh(&mut acc, &pieces, &squares);
+        assert!(
+            acc.values
+                .iter()
+                .zip(self.stack.last().unwrap().values.iter())
+                .all(|(a, b)| a[..] == b[..]),
+            "accumulator mismatch after {:?}",
+            board.board()
+        );
+    }
 }
 
 pub const NNUE_FILE: &'static str = "./nnue/nn-62ef826d1a6d.nnue";
+
+/// A network with small deterministic weights in the layout of the real one,
+/// so that the tests do not depend on the network file being present
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Values in `-range..=range` from a xorshift generator
+    fn weights(seed: &mut u64, n: usize, range: i64) -> impl Iterator<Item = i64> + '_ {
+        (0..n).map(move |_| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            (*seed % (2 * range as u64 + 1)) as i64 - range
+        })
+    }
+
+    pub fn network() -> Network {
+        let mut seed = 0x9E37_79B9_7F4A_7C15;
+        let seed = &mut seed;
+        Network {
+            ft_biases: weights(seed, HALF_DIMENSIONS, 16)
+                .map(|x| x as i16)
+                .collect(),
+            ft_weights: weights(seed, HALF_DIMENSIONS * FT_INPUTS, 8)
+                .map(|x| x as i16)
+                .collect(),
+            l1_biases: weights(seed, L1_SIZE, 512).map(|x| x as i32).collect(),
+            l1_weights: weights(seed, L1_SIZE * HALF_DIMENSIONS * 2, 4)
+                .map(|x| x as i8)
+                .collect(),
+            l2_biases: weights(seed, L2_SIZE, 256).map(|x| x as i32).collect(),
+            l2_weights: weights(seed, L2_SIZE * L1_SIZE, 16)
+                .map(|x| x as i8)
+                .collect(),
+            out_bias: 100,
+            out_weights: weights(seed, L2_SIZE, 64).map(|x| x as i8).collect(),
+        }
+    }
+
+    /// Writes `network` in the format read by `Network::load`
+    pub fn write(network: &Network, path: &str) -> io::Result<()> {
+        let mut out = Vec::new();
+        let desc = b"fixture";
+        for x in [NNUE_VERSION, 0, desc.len() as u32].iter() {
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+        out.extend_from_slice(desc);
+        out.extend_from_slice(&0u32.to_le_bytes());
+        for x in network.ft_biases.iter().chain(network.ft_weights.iter()) {
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+        out.extend_from_slice(&0u32.to_le_bytes());
+        let layers = [
+            (&network.l1_biases[..], &network.l1_weights[..]),
+            (&network.l2_biases[..], &network.l2_weights[..]),
+            (&[network.out_bias][..], &network.out_weights[..]),
+        ];
+        for (biases, weights) in layers.iter() {
+            for x in biases.iter() {
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            out.extend(weights.iter().map(|&x| x as u8));
+        }
+        File::create(path)?.write_all(&out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::uci::Uci;
+    use crate::chess::{Board, Position, Square};
+
+    const FENS: [&str; 6] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        "4k3/8/8/8/8/8/8/4K2Q b - - 10 60",
+        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+    ];
+
+    /// Rotates the board by 180 degrees and swaps the colors,
+    /// which must leave every HalfKP feature unchanged
+    fn rotate_fen(fen: &str) -> String {
+        let parts: Vec<&str> = fen.split(' ').collect();
+        let placement = parts[0]
+            .split('/')
+            .rev()
+            .map(|rank| {
+                rank.chars()
+                    .rev()
+                    .map(|c| {
+                        if c.is_ascii_uppercase() {
+                            c.to_ascii_lowercase()
+                        } else {
+                            c.to_ascii_uppercase()
+                        }
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join("/");
+        let turn = if parts[1] == "w" { "b" } else { "w" };
+        format!("{} {} - - {} {}", placement, turn, parts[4], parts[5])
+    }
+
+    #[test]
+    fn feature_index() {
+        // white pawn on e2 seen by white with king on e1
+        assert_eq!(make_index(0, 12, 6, 4), 13 + PS_END * 4);
+        // the same pawn seen by black with king on e8
+        assert_eq!(
+            make_index(1, 12, 6, 60),
+            PS_B_PAWN + (12 ^ 63) + PS_END * (60 ^ 63)
+        );
+        assert!(make_index(1, 63, 2, 0) < FT_INPUTS);
+    }
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    /// Straightforward evaluation from the description of the architecture,
+    /// sharing nothing with the engine's inference but the weights
+    fn reference(net: &Network, board: &Chess) -> i32 {
+        reference_board(net, board.board(), board.turn())
+    }
+
+    /// `reference` on a bare board, which does not have to be a legal position
+    fn reference_board(net: &Network, b: &Board, turn: Color) -> i32 {
+        let mut halves = [[0i32; HALF_DIMENSIONS]; 2];
+        // the side to move comes first
+        for (half, &color) in [turn, !turn].iter().enumerate() {
+            let flip = |sq: usize| if color == Color::White { sq } else { 63 - sq };
+            let ksq = flip(b.king_of(color).unwrap() as usize);
+            let acc = &mut halves[half];
+            for (a, &bias) in acc.iter_mut().zip(net.ft_biases.iter()) {
+                *a = bias as i32;
+            }
+            for (sq, p) in b.pieces() {
+                if p.role == Role::King {
+                    continue;
+                }
+                // friendly pawn, enemy pawn, friendly knight, ... after the 1 unused entry
+                let kind = (p.role as usize - 1) * 2 + (p.color != color) as usize;
+                let feature = ksq * 641 + 1 + kind * 64 + flip(sq as usize);
+                let w = &net.ft_weights[feature * HALF_DIMENSIONS..];
+                for (a, &w) in acc.iter_mut().zip(w.iter()) {
+                    *a += w as i32;
+                }
+            }
+        }
+
+        let dense = |input: &[i32], biases: &[i32], weights: &[i8]| -> Vec<i32> {
+            biases
+                .iter()
+                .zip(weights.chunks(input.len()))
+                .map(|(&b, w)| {
+                    b + w
+                        .iter()
+                        .zip(input)
+                        .map(|(&w, &x)| w as i32 * x)
+                        .sum::<i32>()
+                })
+                .collect()
+        };
+        let clipped = |x: Vec<i32>, shift: i32| -> Vec<i32> {
+            x.into_iter()
+                .map(|x| (x >> shift).max(0).min(127))
+                .collect()
+        };
+        let input = clipped(halves.concat(), 0);
+        let l1 = clipped(dense(&input, &net.l1_biases, &net.l1_weights), 6);
+        let l2 = clipped(dense(&l1, &net.l2_biases, &net.l2_weights), 6);
+        dense(&l2, &[net.out_bias], &net.out_weights)[0] / 16
+    }
+
+    /// Raw scores of the fixture network for `FENS`, as computed by `reference`
+    const FIXTURE_SCORES: [i32; 6] = [21, -62, -21, -18, -19, -64];
+
+    #[test]
+    fn fixture_file() {
+        let net = fixture::network();
+        let path = std::env::temp_dir().join(format!("iceburn-{}.nnue", std::process::id()));
+        let path = path.to_str().unwrap();
+        fixture::write(&net, path).unwrap();
+        let loaded = Network::load(path);
+
+        // a truncated file is rejected
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() - 1]).unwrap();
+        let truncated = Network::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert!(truncated.is_err());
+        for fen in FENS.iter() {
+            let (turn, pieces, squares) = decode_board(&from_fen(fen));
+            assert_eq!(
+                loaded.evaluate(turn, &pieces, &squares),
+                net.evaluate(turn, &pieces, &squares)
+            );
+        }
+    }
+
+    #[test]
+    fn matches_reference() {
+        let net = fixture::network();
+        for (fen, &expected) in FENS.iter().zip(FIXTURE_SCORES.iter()) {
+            for fen in [fen.to_string(), rotate_fen(fen)].iter() {
+                let board = from_fen(fen);
+                let (turn, pieces, squares) = decode_board(&board);
+                assert_eq!(net.evaluate(turn, &pieces, &squares), expected, "{}", fen);
+                assert_eq!(reference(&net, &board), expected, "{}", fen);
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_matches_reference() {
+        let net = Arc::new(fixture::network());
+        let mut state = NnueState::new(Some(net.clone()));
+        // castling both ways, en passant, a promotion with capture and king moves
+        let games = [
+            (
+                FENS[2],
+                &[
+                    "e1g1", "e8c8", "d5e6", "f7e6", "e5d7", "d8d7", "f3f6", "h3g2", "g1g2",
+                ][..],
+            ),
+            (
+                "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+                &["e5f6", "g8f6", "d2d4", "e8f7"][..],
+            ),
+            (FENS[5], &["d7c8q", "d8c8", "e1f1", "f2h1"][..]),
+        ];
+        for (fen, moves) in games.iter() {
+            let mut board = from_fen(fen);
+            state.reset(&board);
+            assert_eq!(
+                state.evaluate(&board),
+                scale_halfmoves(reference(&net, &board), &board)
+            );
+            for uci in moves.iter() {
+                let m = Uci::from_str(uci).unwrap().to_move(&board).unwrap();
+                let mut after = board.clone();
+                after.play_unchecked(&m);
+                state.push(&board, &m, &after);
+                board = after;
+                assert_eq!(
+                    state.evaluate(&board),
+                    scale_halfmoves(reference(&net, &board), &board),
+                    "{}",
+                    uci
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn piece_values_match_reference() {
+        let net = fixture::network();
+        for fen in FENS.iter() {
+            let board = from_fen(fen);
+            let full = reference(&net, &board);
+            let values = piece_values(&net, &board);
+            // every piece but the kings, each worth what removing it costs
+            assert_eq!(values.len(), board.board().occupied().count() - 2);
+            for (sq, value) in values {
+                let mut b = board.board().clone();
+                b.remove_piece_at(Square::new(sq as u32)).unwrap();
+                let without = reference_board(&net, &b, board.turn());
+                assert_eq!(value, full - without, "{} {}", fen, sq);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "requires ./nnue/nn-62ef826d1a6d.nnue"]
+    fn rotation_parity() {
+        nnue_init(NNUE_FILE).unwrap();
+        for fen in FENS.iter() {
+            assert_eq!(
+                nnue_eval_fen(fen),
+                nnue_eval_fen(&rotate_fen(fen)),
+                "{}",
+                fen
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "requires ./nnue/nn-62ef826d1a6d.nnue"]
+    fn known_values() {
+        nnue_init(NNUE_FILE).unwrap();
+        let net = network().unwrap();
+        let raw = |fen: &str| {
+            let board: Chess =
+                Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap();
+            let (turn, pieces, squares) = decode_board(&board);
+            net.evaluate(turn, &pieces, &squares)
+        };
+
+        // the opening is roughly balanced, the lone queen is winning
+        assert!(raw(FENS[0]).abs() < 150);
+        assert!(raw(FENS[4]) < -500);
+        // the scaling by the halfmove clock is applied on top of the raw score
+        assert_eq!(nnue_eval_fen(FENS[0]), raw(FENS[0]) * 90 / 100);
+    }
+
+    #[test]
+    #[ignore = "requires ./nnue/nn-62ef826d1a6d.nnue"]
+    fn piece_value_estimates() {
+        nnue_init(NNUE_FILE).unwrap();
+        let net = network().unwrap();
+        let board: Chess =
+            Chess::from_setup(&Fen::from_str(FENS[4]).unwrap(), CastlingMode::Standard).unwrap();
+
+        // only the queen is listed, and it is worth a lot to White with Black to move
+        let values = piece_values(&net, &board);
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].0, 7);
+        assert!(values[0].1 < -500);
+        assert!((halfmove_scale(&board) - 0.9).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,405 @@
+use std::fmt;
+
+use crate::book::BookStrategy;
+use crate::nnue::{nnue_init, NNUE_FILE};
+use crate::pruning::Pruning;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OptionKind {
+    Check {
+        default: bool,
+    },
+    Spin {
+        default: i64,
+        min: i64,
+        max: i64,
+    },
+    Combo {
+        default: &'static str,
+        vars: &'static [&'static str],
+    },
+    Button,
+    String {
+        default: &'static str,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UciOption {
+    pub name: &'static str,
+    pub kind: OptionKind,
+}
+
+impl fmt::Display for UciOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "option name {} type ", self.name)?;
+        match self.kind {
+            OptionKind::Check { default } => write!(f, "check default {}", default),
+            OptionKind::Spin { default, min, max } => {
+                write!(f, "spin default {} min {} max {}", default, min, max)
+            }
+            OptionKind::Combo { default, vars } => {
+                write!(f, "combo default {}", default)?;
+                for var in vars {
+                    write!(f, " var {}", var)?;
+                }
+                Ok(())
+            }
+            OptionKind::Button => write!(f, "button"),
+            OptionKind::String { default } => write!(
+                f,
+                "string default {}",
+                if default.is_empty() {
+                    "<empty>"
+                } else {
+                    default
+                }
+            ),
+        }
+    }
+}
+
+/// Every option advertised in response to `uci`
+pub const OPTIONS: &[UciOption] = &[
+    UciOption {
+        name: "Hash",
+        kind: OptionKind::Spin {
+            default: 32,
+            min: 1,
+            max: 65536,
+        },
+    },
+    UciOption {
+        name: "Clear Hash",
+        kind: OptionKind::Button,
+    },
+    UciOption {
+        name: "Threads",
+        kind: OptionKind::Spin {
+            default: 1,
+            min: 1,
+            max: 256,
+        },
+    },
+    UciOption {
+        name: "Ponder",
+        kind: OptionKind::Check { default: false },
+    },
+    UciOption {
+        name: "MultiPV",
+        kind: OptionKind::Spin {
+            default: 1,
+            min: 1,
+            max: 64,
+        },
+    },
+    UciOption {
+        name: "Move Overhead",
+        kind: OptionKind::Spin {
+            default: 10,
+            min: 0,
+            max: 5000,
+        },
+    },
+    UciOption {
+        name: "EvalFile",
+        kind: OptionKind::String { default: NNUE_FILE },
+    },
+    UciOption {
+        name: "Use NNUE",
+        kind: OptionKind::Check { default: true },
+    },
+    UciOption {
+        name: "SyzygyPath",
+        kind: OptionKind::String { default: "" },
+    },
+    UciOption {
+        name: "OwnBook",
+        kind: OptionKind::Check { default: false },
+    },
+    UciOption {
+        name: "BookFile",
+        kind: OptionKind::String { default: "" },
+    },
+    UciOption {
+        name: "Book Strategy",
+        kind: OptionKind::Combo {
+            default: "Best",
+            vars: &["Best", "Weighted", "Variety"],
+        },
+    },
+    UciOption {
+        name: "Reverse Futility Pruning",
+        kind: OptionKind::Check { default: true },
+    },
+    UciOption {
+        name: "Reverse Futility Margin",
+        kind: OptionKind::Spin {
+            default: 90,
+            min: 0,
+            max: 1000,
+        },
+    },
+    UciOption {
+        name: "Futility Pruning",
+        kind: OptionKind::Check { default: true },
+    },
+    UciOption {
+        name: "Futility Margin",
+        kind: OptionKind::Spin {
+            default: 150,
+            min: 0,
+            max: 1000,
+        },
+    },
+    UciOption {
+        name: "Razoring",
+        kind: OptionKind::Check { default: true },
+    },
+    UciOption {
+        name: "Razor Margin",
+        kind: OptionKind::Spin {
+            default: 300,
+            min: 0,
+            max: 1000,
+        },
+    },
+    UciOption {
+        name: "Late Move Pruning",
+        kind: OptionKind::Check { default: true },
+    },
+    UciOption {
+        name: "Late Move Pruning Base",
+        kind: OptionKind::Spin {
+            default: 3,
+            min: 0,
+            max: 64,
+        },
+    },
+    UciOption {
+        name: "Delta Pruning",
+        kind: OptionKind::Check { default: true },
+    },
+    UciOption {
+        name: "Delta Margin",
+        kind: OptionKind::Spin {
+            default: 200,
+            min: 0,
+            max: 1000,
+        },
+    },
+];
+
+/// Current values of the options
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EngineOptions {
+    pub hash: u64,
+    pub threads: usize,
+    pub ponder: bool,
+    pub multi_pv: usize,
+    pub move_overhead: u64,
+    pub eval_file: String,
+    pub use_nnue: bool,
+    pub syzygy_path: String,
+    pub own_book: bool,
+    pub book_file: String,
+    pub book_strategy: BookStrategy,
+    pub pruning: Pruning,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        EngineOptions {
+            hash: 32,
+            threads: 1,
+            ponder: false,
+            multi_pv: 1,
+            move_overhead: 10,
+            eval_file: NNUE_FILE.to_string(),
+            use_nnue: true,
+            syzygy_path: String::new(),
+            own_book: false,
+            book_file: String::new(),
+            book_strategy: BookStrategy::Best,
+            pruning: Pruning::default(),
+        }
+    }
+}
+
+/// Splits the arguments of `setoption` into the name and the optional value
+pub fn parse_setoption(args: &str) -> Option<(&str, Option<&str>)> {
+    let args = args.trim();
+    if !args.starts_with("name") {
+        return None;
+    }
+    let args = args[4..].trim();
+    let (name, value) = match args.find(" value") {
+        Some(idx) => (args[..idx].trim(), Some(args[idx + 6..].trim())),
+        None => (args, None),
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some((name, value))
+    }
+}
+
+/// Looks an option up, UCI option names are case insensitive
+pub fn find_option(name: &str) -> Option<&'static UciOption> {
+    OPTIONS.iter().find(|x| x.name.eq_ignore_ascii_case(name))
+}
+
+impl EngineOptions {
+    /// Validates and stores a value, returning the option that was set
+    /// so that the caller can apply its side effects
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<&'static UciOption, String> {
+        let option = find_option(name).ok_or_else(|| format!("No such option: {}", name))?;
+        let value = value.unwrap_or("");
+
+        match option.kind {
+            OptionKind::Check { .. } => {
+                let v = match value.to_ascii_lowercase().as_str() {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(format!("{} expects true or false", option.name)),
+                };
+                match option.name {
+                    "Ponder" => self.ponder = v,
+                    "Use NNUE" => self.use_nnue = v,
+                    "OwnBook" => self.own_book = v,
+                    "Reverse Futility Pruning" => self.pruning.rfp = v,
+                    "Futility Pruning" => self.pruning.futility = v,
+                    "Razoring" => self.pruning.razoring = v,
+                    "Late Move Pruning" => self.pruning.lmp = v,
+                    "Delta Pruning" => self.pruning.delta = v,
+                    _ => {}
+                }
+            }
+            OptionKind::Spin { min, max, .. } => {
+                let v: i64 = value
+                    .parse()
+                    .map_err(|_| format!("{} expects an integer", option.name))?;
+                if v < min || v > max {
+                    return Err(format!(
+                        "{} must be between {} and {}",
+                        option.name, min, max
+                    ));
+                }
+                match option.name {
+                    "Hash" => self.hash = v as u64,
+                    "Threads" => self.threads = v as usize,
+                    "MultiPV" => self.multi_pv = v as usize,
+                    "Move Overhead" => self.move_overhead = v as u64,
+                    "Reverse Futility Margin" => self.pruning.rfp_margin = v as i32,
+                    "Futility Margin" => self.pruning.futility_margin = v as i32,
+                    "Razor Margin" => self.pruning.razor_margin = v as i32,
+                    "Late Move Pruning Base" => self.pruning.lmp_base = v as usize,
+                    "Delta Margin" => self.pruning.delta_margin = v as i32,
+                    _ => {}
+                }
+            }
+            OptionKind::Combo { vars, .. } => {
+                if !vars.iter().any(|x| x.eq_ignore_ascii_case(value)) {
+                    return Err(format!(
+                        "{} must be one of {}",
+                        option.name,
+                        vars.join(", ")
+                    ));
+                }
+                if option.name == "Book Strategy" {
+                    self.book_strategy = value.parse()?;
+                }
+            }
+            OptionKind::Button => {}
+            OptionKind::String { .. } => {
+                let v = if value == "<empty>" { "" } else { value };
+                match option.name {
+                    // a network that fails to load is rejected, the previous one stays in use
+                    "EvalFile" => {
+                        nnue_init(v).map_err(|x| format!("{}: {}", v, x))?;
+                        self.eval_file = v.to_string();
+                    }
+                    "SyzygyPath" => self.syzygy_path = v.to_string(),
+                    "BookFile" => self.book_file = v.to_string(),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(option)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_setoption("name Hash value 128"),
+            Some(("Hash", Some("128")))
+        );
+        assert_eq!(
+            parse_setoption("name Move Overhead value 30"),
+            Some(("Move Overhead", Some("30")))
+        );
+        assert_eq!(
+            parse_setoption("name EvalFile value ./nets/my net.nnue"),
+            Some(("EvalFile", Some("./nets/my net.nnue")))
+        );
+        assert_eq!(
+            parse_setoption("name Clear Hash"),
+            Some(("Clear Hash", None))
+        );
+        assert_eq!(parse_setoption("Hash value 1"), None);
+    }
+
+    #[test]
+    fn set_values() {
+        let mut options = EngineOptions::default();
+        assert!(options.set("hash", Some("256")).is_ok());
+        assert!(options.set("Threads", Some("4")).is_ok());
+        assert!(options.set("Use NNUE", Some("false")).is_ok());
+        assert_eq!(options.hash, 256);
+        assert_eq!(options.threads, 4);
+        assert!(!options.use_nnue);
+
+        assert!(options.set("Threads", Some("0")).is_err());
+        assert!(options.set("MultiPV", Some("abc")).is_err());
+        assert!(options.set("Nonexistent", Some("1")).is_err());
+        assert_eq!(options.threads, 4);
+
+        assert!(options.set("Book Strategy", Some("weighted")).is_ok());
+        assert_eq!(options.book_strategy, BookStrategy::Weighted);
+        assert!(options.set("Book Strategy", Some("Worst")).is_err());
+        assert_eq!(options.book_strategy, BookStrategy::Weighted);
+
+        assert!(options.set("Razoring", Some("false")).is_ok());
+        assert!(options.set("Futility Margin", Some("120")).is_ok());
+        assert!(!options.pruning.razoring);
+        assert_eq!(options.pruning.futility_margin, 120);
+        assert!(options.set("Delta Margin", Some("-1")).is_err());
+
+        assert!(options
+            .set("EvalFile", Some("./does/not/exist.nnue"))
+            .is_err());
+        assert_eq!(options.eval_file, NNUE_FILE);
+    }
+
+    #[test]
+    fn advertise() {
+        assert_eq!(
+            OPTIONS[0].to_string(),
+            "option name Hash type spin default 32 min 1 max 65536"
+        );
+        assert_eq!(
+            find_option("clear hash").unwrap().to_string(),
+            "option name Clear Hash type button"
+        );
+        assert_eq!(
+            find_option("SyzygyPath").unwrap().to_string(),
+            "option name SyzygyPath type string default <empty>"
+        );
+    }
+}
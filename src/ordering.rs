@@ -1,7 +1,8 @@
 use shakmaty::Chess;
 
-use crate::chess::{Move, MoveList, Setup};
+use crate::chess::{Move, MoveList, Position, Setup};
 use crate::search::Depth;
+use crate::see::see_ge;
 
 #[rustfmt::skip]
 pub const MMV_LVA: [u16; 36] = [
@@ -13,10 +14,27 @@ pub const MMV_LVA: [u16; 36] = [
     100, 200, 300, 400, 500, 600,
 ];
 
+/// The moves played one and two plies before a node, the most recent first
+pub type Continuation = [Option<Move>; 2];
+
+/// Bound of every history table, updates pull entries towards it
+/// so that they never overflow and stay responsive
+pub const MAX_HISTORY: i32 = 16384;
+
+/// Piece-to index of a move, the side is implied by the distance to the node
+#[inline]
+fn piece_to(m: &Move) -> usize {
+    (m.role() as usize - 1) * 64 + m.to() as usize
+}
+
 #[derive(Debug)]
 pub struct OrderingHistory {
-    pub history_moves: [[u16; 64]; 64],
+    pub history_moves: [[i16; 64]; 64],
     pub killer_moves: [[Option<Move>; 512]; 2],
+    /// the move that refuted each piece-to of the previous move
+    pub counter_moves: Vec<Option<Move>>,
+    /// piece-to of a quiet by piece-to of the moves one and two plies before
+    pub continuation_history: [Vec<i16>; 2],
 }
 
 macro_rules! none_512 {
@@ -70,21 +88,69 @@ impl Default for OrderingHistory {
         OrderingHistory {
             history_moves: [[0; 64]; 64],
             killer_moves: [NONE512, NONE512],
+            counter_moves: vec![None; 6 * 64],
+            continuation_history: [vec![0; 6 * 64 * 6 * 64], vec![0; 6 * 64 * 6 * 64]],
         }
     }
 }
 
-const HIS_CAP: u16 = u16::MAX / 2 - 1;
+/// Gravity update: the bonus shrinks as the entry nears the bound
+#[inline]
+fn apply_bonus(entry: &mut i16, bonus: i32) {
+    let v = *entry as i32;
+    *entry = (v + bonus - v * bonus.abs() / MAX_HISTORY) as i16;
+}
 
 impl OrderingHistory {
+    #[inline]
+    fn history_bonus(depth: Depth) -> i32 {
+        (depth as i32 * depth as i32 * 16).min(1600)
+    }
+
+    fn update_history(&mut self, m: &Move, cont: &Continuation, bonus: i32) {
+        apply_bonus(
+            &mut self.history_moves[m.from().unwrap() as usize][m.to() as usize],
+            bonus,
+        );
+        for (table, prev) in self.continuation_history.iter_mut().zip(cont.iter()) {
+            if let Some(prev) = prev {
+                apply_bonus(&mut table[piece_to(prev) * 6 * 64 + piece_to(m)], bonus);
+            }
+        }
+    }
+
+    /// Rewards a quiet move that caused a cutoff
     /// https://www.chessprogramming.org/History_Heuristic
-    pub fn add_history(&mut self, m: &Move, depth: Depth) {
-        self.history_moves[m.from().unwrap() as usize][m.to() as usize] +=
-            depth as u16 * depth as u16;
-        if self.history_moves[m.from().unwrap() as usize][m.to() as usize] > HIS_CAP {
-            self.history_moves
-                .iter_mut()
-                .for_each(|x| x.iter_mut().for_each(|y| *y /= 2));
+    pub fn add_history(&mut self, m: &Move, depth: Depth, cont: &Continuation) {
+        self.update_history(m, cont, Self::history_bonus(depth));
+    }
+
+    /// Punishes a quiet move searched before the one that caused a cutoff
+    pub fn add_malus(&mut self, m: &Move, depth: Depth, cont: &Continuation) {
+        self.update_history(m, cont, -Self::history_bonus(depth));
+    }
+
+    /// Main history plus both continuation histories of a quiet move
+    pub fn quiet_score(&self, m: &Move, cont: &Continuation) -> i32 {
+        let mut score = self.history_moves[m.from().unwrap() as usize][m.to() as usize] as i32;
+        for (table, prev) in self.continuation_history.iter().zip(cont.iter()) {
+            if let Some(prev) = prev {
+                score += table[piece_to(prev) * 6 * 64 + piece_to(m)] as i32;
+            }
+        }
+        score
+    }
+
+    /// https://www.chessprogramming.org/Countermove_Heuristic
+    pub fn counter_move(&self, cont: &Continuation) -> Option<Move> {
+        cont[0]
+            .as_ref()
+            .and_then(|prev| self.counter_moves[piece_to(prev)].clone())
+    }
+
+    pub fn add_counter_move(&mut self, cont: &Continuation, m: Move) {
+        if let Some(prev) = &cont[0] {
+            self.counter_moves[piece_to(prev)] = Some(m);
         }
     }
 
@@ -103,76 +169,217 @@ impl OrderingHistory {
     }
 }
 
+const HASH_MOVE: i32 = 4_000_000;
+const GOOD_CAPTURE: i32 = 3_000_000;
+const PROMOTION: i32 = 2_500_000;
+const KILLER: i32 = 2_000_000;
+const COUNTER_MOVE: i32 = 1_900_000;
+const BAD_CAPTURE: i32 = -1_000_000;
+
+#[inline]
+fn mvv_lva(m: &Move) -> i32 {
+    MMV_LVA[(m.role() as usize - 1) * 6 + (m.capture().unwrap() as usize - 1)] as i32
+}
+
+/// Moves are generated and tried in this order,
+/// so that a cutoff on an early move skips generating the later ones
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    HashMove,
+    GenerateCaptures,
+    GoodCaptures,
+    Killers,
+    CounterMove,
+    GenerateQuiets,
+    Quiets,
+    BadCaptures,
+    Done,
+}
+
+/// Staged move picker
+/// https://www.chessprogramming.org/Move_Ordering
 #[derive(Debug)]
 pub struct MoveOrderer {
-    pub ml: MoveList,
-    pub score_list: Vec<Option<u16>>,
-    pub index: usize,
+    pub stage: Stage,
+    hash_move: Option<Move>,
+    killer: Option<Move>,
+    counter_move: Option<Move>,
+    cont: Continuation,
+    /// moves to pick from instead of generating them, at the root
+    given: Option<MoveList>,
+    captures_only: bool,
+    ml: MoveList,
+    score_list: Vec<i32>,
+    index: usize,
+    bad_captures: MoveList,
 }
 
 impl MoveOrderer {
-    pub fn new(ml: MoveList) -> Self {
-        let l = ml.len();
+    fn with(
+        hash_move: Option<Move>,
+        cont: Continuation,
+        given: Option<MoveList>,
+        captures_only: bool,
+    ) -> Self {
         MoveOrderer {
-            ml,
-            score_list: vec![None; l],
+            stage: Stage::HashMove,
+            hash_move,
+            killer: None,
+            counter_move: None,
+            cont,
+            given,
+            captures_only,
+            ml: MoveList::new(),
+            score_list: Vec::new(),
             index: 0,
+            bad_captures: MoveList::new(),
         }
     }
 
+    /// Every legal move, `hash_move` must be legal
+    pub fn new(hash_move: Option<Move>, cont: Continuation) -> Self {
+        Self::with(hash_move, cont, None, false)
+    }
+
+    /// Captures that do not lose material, for the quiescence search
+    pub fn captures(hash_move: Option<Move>) -> Self {
+        Self::with(
+            hash_move.filter(|m| m.is_capture()),
+            [None, None],
+            None,
+            true,
+        )
+    }
+
+    /// The moves of `ml` only, at the root
+    pub fn with_moves(ml: MoveList, hash_move: Option<Move>) -> Self {
+        let hash_move = hash_move.filter(|m| ml.contains(m));
+        Self::with(hash_move, [None, None], Some(ml), false)
+    }
+
+    /// Hash move first, then captures that win or trade material by MVV-LVA,
+    /// promotions, the killer, the countermove, quiets by history
+    /// and last the captures losing material
     pub fn score_of(
-        &self,
         m: &Move,
         oh: &OrderingHistory,
         hash_move: &Option<Move>,
         board: &Chess,
         ply: usize,
-    ) -> u16 {
-        let mut score = 0;
-
+        cont: &Continuation,
+    ) -> i32 {
         if let Some(hash_move) = hash_move {
             if m == hash_move {
-                score += 15000;
+                return HASH_MOVE;
             }
         }
 
-        if oh.is_killer(board, m.clone(), ply) {
-            score += 9000;
-        }
-
         if m.is_capture() {
-            score +=
-                MMV_LVA[(m.role() as usize - 1) * 6 + (m.capture().unwrap() as usize - 1)] + 200;
+            return if see_ge(board, m, 0) {
+                GOOD_CAPTURE + mvv_lva(m)
+            } else {
+                BAD_CAPTURE + mvv_lva(m)
+            };
         }
         if m.is_promotion() {
-            score += 5000;
+            return PROMOTION;
         }
-        if !m.is_capture() && !m.is_promotion() {
-            score += oh.history_moves[m.from().unwrap() as usize][m.to() as usize];
+
+        if oh.is_killer(board, m.clone(), ply) {
+            return KILLER;
+        }
+        if oh.counter_move(cont).as_ref() == Some(m) {
+            return COUNTER_MOVE;
+        }
+
+        oh.quiet_score(m, cont)
+    }
+
+    /// Whether a killer or countermove can be played here and was not tried yet
+    fn is_refutation(&self, board: &Chess, m: &Move) -> bool {
+        let legal = match &self.given {
+            Some(given) => given.contains(m),
+            None => board.is_legal(m),
         };
+        legal
+            && !Self::is_tactical(m)
+            && Some(m) != self.hash_move.as_ref()
+            && Some(m) != self.killer.as_ref()
+    }
 
-        score
+    #[inline]
+    fn is_tactical(m: &Move) -> bool {
+        m.is_capture() || m.is_promotion()
     }
 
-    pub fn next_move(
-        &mut self,
-        oh: &OrderingHistory,
-        hash_move: &Option<Move>,
-        board: &Chess,
-        ply: usize,
-    ) -> Option<Move> {
+    fn generate_captures(&mut self, board: &Chess) {
+        let mut ml = match &self.given {
+            Some(given) => given.clone(),
+            None => {
+                let mut ml = board.capture_moves();
+                if !self.captures_only {
+                    ml.extend(
+                        board
+                            .promotion_moves()
+                            .into_iter()
+                            .filter(|m| !m.is_capture()),
+                    );
+                }
+                ml
+            }
+        };
+        let hash_move = &self.hash_move;
+        ml.retain(|m| Self::is_tactical(m) && Some(&*m) != hash_move.as_ref());
+
+        self.ml.clear();
+        self.bad_captures.clear();
+        self.score_list.clear();
+        for m in ml {
+            if m.is_capture() && !see_ge(board, &m, 0) {
+                self.bad_captures.push(m);
+            } else {
+                self.score_list.push(if m.is_capture() {
+                    GOOD_CAPTURE + mvv_lva(&m)
+                } else {
+                    PROMOTION
+                });
+                self.ml.push(m);
+            }
+        }
+        self.index = 0;
+    }
+
+    fn generate_quiets(&mut self, oh: &OrderingHistory, board: &Chess, ply: usize) {
+        let mut ml = match &self.given {
+            Some(given) => given.clone(),
+            None => board.legal_moves(),
+        };
+        let tried = [&self.hash_move, &self.killer, &self.counter_move];
+        ml.retain(|m| !Self::is_tactical(m) && !tried.iter().any(|x| x.as_ref() == Some(&*m)));
+
+        self.score_list.clear();
+        for m in ml.iter() {
+            self.score_list.push(Self::score_of(
+                m,
+                oh,
+                &self.hash_move,
+                board,
+                ply,
+                &self.cont,
+            ));
+        }
+        self.ml = ml;
+        self.index = 0;
+    }
+
+    /// Selects the best scored move left in the current stage
+    fn pick_best(&mut self) -> Option<Move> {
         if self.index >= self.ml.len() {
             return None;
         }
 
         let mut max = self.index;
-        if self.score_list[max].is_none() {
-            self.score_list[max] = Some(self.score_of(&self.ml[max], oh, hash_move, board, ply));
-        }
         for j in self.index + 1..self.ml.len() {
-            if self.score_list[j].is_none() {
-                self.score_list[j] = Some(self.score_of(&self.ml[j], oh, hash_move, board, ply));
-            }
             if self.score_list[j] > self.score_list[max] {
                 max = j;
             }
@@ -187,4 +394,235 @@ impl MoveOrderer {
 
         Some(self.ml[self.index - 1].clone())
     }
+
+    pub fn next_move(&mut self, oh: &OrderingHistory, board: &Chess, ply: usize) -> Option<Move> {
+        loop {
+            match self.stage {
+                Stage::HashMove => {
+                    self.stage = Stage::GenerateCaptures;
+                    if self.hash_move.is_some() {
+                        return self.hash_move.clone();
+                    }
+                }
+                Stage::GenerateCaptures => {
+                    self.generate_captures(board);
+                    self.stage = Stage::GoodCaptures;
+                }
+                Stage::GoodCaptures => {
+                    if let Some(m) = self.pick_best() {
+                        return Some(m);
+                    }
+                    self.stage = if self.captures_only {
+                        Stage::Done
+                    } else {
+                        Stage::Killers
+                    };
+                }
+                Stage::Killers => {
+                    self.stage = Stage::CounterMove;
+                    let killer = oh.killer_moves[board.turn() as usize][ply].clone();
+                    if let Some(killer) = killer {
+                        if self.is_refutation(board, &killer) {
+                            self.killer = Some(killer.clone());
+                            return Some(killer);
+                        }
+                    }
+                }
+                Stage::CounterMove => {
+                    self.stage = Stage::GenerateQuiets;
+                    if let Some(counter_move) = oh.counter_move(&self.cont) {
+                        if self.is_refutation(board, &counter_move) {
+                            self.counter_move = Some(counter_move.clone());
+                            return Some(counter_move);
+                        }
+                    }
+                }
+                Stage::GenerateQuiets => {
+                    self.generate_quiets(oh, board, ply);
+                    self.stage = Stage::Quiets;
+                }
+                Stage::Quiets => {
+                    if let Some(m) = self.pick_best() {
+                        return Some(m);
+                    }
+                    self.ml = std::mem::take(&mut self.bad_captures);
+                    self.score_list = self.ml.iter().map(|m| BAD_CAPTURE + mvv_lva(m)).collect();
+                    self.index = 0;
+                    self.stage = Stage::BadCaptures;
+                }
+                Stage::BadCaptures => {
+                    if let Some(m) = self.pick_best() {
+                        return Some(m);
+                    }
+                    self.stage = Stage::Done;
+                }
+                Stage::Done => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::{CastlingMode, FromSetup};
+
+    /// Perft through the picker, checking at every node that it yields each legal move
+    /// exactly once and in the order of `score_of`
+    fn walk(
+        board: &Chess,
+        depth: usize,
+        ply: usize,
+        cont: &Continuation,
+        oh: &mut OrderingHistory,
+    ) -> usize {
+        let legal = board.legal_moves();
+        if depth == 0 {
+            return 1;
+        }
+
+        let hash_move = if ply % 2 == 0 && !legal.is_empty() {
+            Some(legal[(depth * 7) % legal.len()].clone())
+        } else {
+            None
+        };
+
+        let mut orderer = MoveOrderer::new(hash_move.clone(), cont.clone());
+        let mut picked = Vec::new();
+        while let Some(m) = orderer.next_move(oh, board, ply) {
+            picked.push(m);
+        }
+        assert_eq!(picked.len(), legal.len());
+        assert!(legal.iter().all(|m| picked.contains(m)));
+
+        let scores: Vec<i32> = picked
+            .iter()
+            .map(|m| MoveOrderer::score_of(m, oh, &hash_move, board, ply, cont))
+            .collect();
+        assert!(scores.windows(2).all(|x| x[0] >= x[1]), "{:?}", scores);
+
+        let mut nodes = 0;
+        for m in picked {
+            let mut nb = board.clone();
+            nb.play_unchecked(&m);
+            let next = [Some(m.clone()), cont[0].clone()];
+            nodes += walk(&nb, depth - 1, ply + 1, &next, oh);
+
+            // feed the heuristics so that siblings see killers, countermoves and history
+            if !m.is_capture() && !m.is_promotion() {
+                match nodes % 3 {
+                    0 => {
+                        oh.add_killer(board, m.clone(), ply);
+                        oh.add_history(&m, depth as Depth, cont);
+                    }
+                    1 => {
+                        oh.add_counter_move(cont, m.clone());
+                        oh.add_malus(&m, depth as Depth, cont);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        nodes
+    }
+
+    fn perft(fen: &str, depth: usize) -> usize {
+        let board: Chess =
+            Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap();
+        walk(
+            &board,
+            depth,
+            0,
+            &[None, None],
+            &mut OrderingHistory::default(),
+        )
+    }
+
+    #[test]
+    fn staged_perft() {
+        assert_eq!(
+            perft(
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                3
+            ),
+            8902
+        );
+        assert_eq!(
+            perft(
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                3
+            ),
+            97862
+        );
+        assert_eq!(
+            perft(
+                "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+                3
+            ),
+            9467
+        );
+        assert_eq!(perft("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 4), 43238);
+    }
+
+    #[test]
+    fn stages() {
+        let board: Chess = Chess::from_setup(
+            &Fen::from_str("4k3/8/2p5/3p4/4P3/8/3Q4/4K3 w - - 0 1").unwrap(),
+            CastlingMode::Standard,
+        )
+        .unwrap();
+        let oh = OrderingHistory::default();
+        let uci = |x: &str| {
+            crate::chess::uci::Uci::from_str(x)
+                .unwrap()
+                .to_move(&board)
+                .unwrap()
+        };
+
+        // exd5 wins a pawn, Qxd5 loses the queen and comes last
+        let mut orderer = MoveOrderer::new(Some(uci("e1f1")), [None, None]);
+        assert_eq!(orderer.next_move(&oh, &board, 0), Some(uci("e1f1")));
+        assert_eq!(orderer.next_move(&oh, &board, 0), Some(uci("e4d5")));
+        let mut last = None;
+        while let Some(m) = orderer.next_move(&oh, &board, 0) {
+            assert_ne!(m, uci("e1f1"));
+            last = Some(m);
+        }
+        assert_eq!(last, Some(uci("d2d5")));
+
+        // the quiescence search only sees captures that hold material
+        let mut orderer = MoveOrderer::captures(Some(uci("e1f1")));
+        assert_eq!(orderer.next_move(&oh, &board, 0), Some(uci("e4d5")));
+        assert_eq!(orderer.next_move(&oh, &board, 0), None);
+    }
+
+    #[test]
+    fn history_gravity() {
+        let board = Chess::default();
+        let mut oh = OrderingHistory::default();
+        let m = board.legal_moves()[0].clone();
+        let prev = board.legal_moves()[1].clone();
+        let cont = [Some(prev), None];
+
+        for _ in 0..1000 {
+            oh.add_history(&m, 30, &cont);
+        }
+        let top = oh.quiet_score(&m, &cont);
+        assert!(top > MAX_HISTORY && top <= 2 * MAX_HISTORY);
+
+        // maluses pull it back below zero
+        for _ in 0..1000 {
+            oh.add_malus(&m, 30, &cont);
+        }
+        let bottom = oh.quiet_score(&m, &cont);
+        assert!(bottom < -MAX_HISTORY && bottom >= -2 * MAX_HISTORY);
+
+        assert_eq!(oh.counter_move(&cont), None);
+        oh.add_counter_move(&cont, m.clone());
+        assert_eq!(oh.counter_move(&cont), Some(m));
+        assert_eq!(oh.counter_move(&[None, None]), None);
+    }
 }
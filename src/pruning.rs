@@ -1 +1,150 @@
+use crate::chess::Move;
+use crate::search::Depth;
+use crate::see::SEE_VALUES;
 
+/// Deepest node reverse futility pruning applies to
+pub const RFP_MAX_DEPTH: Depth = 6;
+/// Deepest node razoring applies to
+pub const RAZOR_MAX_DEPTH: Depth = 2;
+/// Deepest node late move pruning applies to
+pub const LMP_MAX_DEPTH: Depth = 4;
+
+/// Switches and margins of the forward pruning techniques,
+/// all of them are exposed as UCI options so that they can be tested one by one.
+/// None of them apply at PV nodes, in check or in a mate search
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pruning {
+    pub rfp: bool,
+    pub rfp_margin: i32,
+    pub futility: bool,
+    pub futility_margin: i32,
+    pub razoring: bool,
+    pub razor_margin: i32,
+    pub lmp: bool,
+    pub lmp_base: usize,
+    pub delta: bool,
+    pub delta_margin: i32,
+}
+
+impl Default for Pruning {
+    fn default() -> Self {
+        Pruning {
+            rfp: true,
+            rfp_margin: 90,
+            futility: true,
+            futility_margin: 150,
+            razoring: true,
+            razor_margin: 300,
+            lmp: true,
+            lmp_base: 3,
+            delta: true,
+            delta_margin: 200,
+        }
+    }
+}
+
+impl Pruning {
+    /// Reverse futility pruning: the static evaluation beats beta by a margin
+    /// growing with depth, the node is assumed to fail high
+    /// https://www.chessprogramming.org/Reverse_Futility_Pruning
+    #[inline]
+    pub fn reverse_futility(&self, depth: Depth, static_eval: i32, beta: i32) -> bool {
+        self.rfp && depth <= RFP_MAX_DEPTH && static_eval - self.rfp_margin * depth as i32 >= beta
+    }
+
+    /// Futility pruning: at frontier nodes, quiet moves cannot bring
+    /// a static evaluation this far below alpha back up
+    /// https://www.chessprogramming.org/Futility_Pruning
+    #[inline]
+    pub fn futility(&self, depth: Depth, static_eval: i32, alpha: i32) -> bool {
+        self.futility && depth == 1 && static_eval + self.futility_margin <= alpha
+    }
+
+    /// Razoring: near the leaves, a static evaluation far below alpha
+    /// sends the node to the quiescence search to confirm the fail low
+    /// https://www.chessprogramming.org/Razoring
+    #[inline]
+    pub fn razor(&self, depth: Depth, static_eval: i32, alpha: i32) -> bool {
+        self.razoring
+            && depth <= RAZOR_MAX_DEPTH
+            && static_eval + self.razor_margin * (depth as i32) < alpha
+    }
+
+    /// Late move pruning: near the leaves, quiet moves ordered after
+    /// enough others are skipped
+    /// https://www.chessprogramming.org/Futility_Pruning#MoveCountBasedPruning
+    #[inline]
+    pub fn late_move(&self, depth: Depth, move_index: usize) -> bool {
+        self.lmp
+            && depth <= LMP_MAX_DEPTH
+            && move_index >= self.lmp_base + (depth as usize * depth as usize)
+    }
+
+    /// Delta pruning: in the quiescence search, a capture that does not raise
+    /// the stand pat to alpha even with a margin is skipped, promotions are always tried
+    /// https://www.chessprogramming.org/Delta_Pruning
+    #[inline]
+    pub fn delta(&self, stand_pat: i32, m: &Move, alpha: i32) -> bool {
+        if !self.delta || m.is_promotion() {
+            return false;
+        }
+        let gain = m.capture().map_or(0, |x| SEE_VALUES[x as usize - 1]);
+        stand_pat + gain + self.delta_margin < alpha
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::{Role, Square};
+
+    fn capture(capture: Role) -> Move {
+        Move::Normal {
+            role: Role::Queen,
+            from: Square::D1,
+            capture: Some(capture),
+            to: Square::D8,
+            promotion: None,
+        }
+    }
+
+    #[test]
+    fn margins() {
+        let p = Pruning::default();
+
+        assert!(p.reverse_futility(1, 500, 300));
+        assert!(!p.reverse_futility(3, 500, 300));
+        assert!(!p.reverse_futility(RFP_MAX_DEPTH + 1, 5000, 0));
+
+        assert!(p.futility(1, 0, 200));
+        assert!(!p.futility(1, 100, 200));
+        assert!(!p.futility(2, 0, 200));
+
+        assert!(p.razor(1, 0, 400));
+        assert!(!p.razor(2, 0, 400));
+
+        assert!(!p.late_move(2, 6));
+        assert!(p.late_move(2, 7));
+        assert!(!p.late_move(LMP_MAX_DEPTH + 1, 200));
+
+        assert!(p.delta(0, &capture(Role::Pawn), 400));
+        assert!(!p.delta(0, &capture(Role::Rook), 400));
+    }
+
+    #[test]
+    fn switches() {
+        let p = Pruning {
+            rfp: false,
+            futility: false,
+            razoring: false,
+            lmp: false,
+            delta: false,
+            ..Pruning::default()
+        };
+        assert!(!p.reverse_futility(1, 5000, 0));
+        assert!(!p.futility(1, -5000, 0));
+        assert!(!p.razor(1, -5000, 0));
+        assert!(!p.late_move(1, 200));
+        assert!(!p.delta(-5000, &capture(Role::Pawn), 0));
+    }
+}
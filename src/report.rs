@@ -0,0 +1,195 @@
+use std::fmt::Write;
+
+use crate::chess::fen::Fen;
+use crate::chess::{Chess, Piece, Position, Setup};
+use crate::nnue::{decode_board, halfmove_scale, piece_values, scale_halfmoves, Network};
+use crate::weight::{evaluation, game_phase, MAX_PHASE};
+use crate::zobrist;
+
+/// Every square of the board with its piece, a1 first
+fn squares(board: &Chess) -> [Option<Piece>; 64] {
+    let mut squares = [None; 64];
+    for (sq, p) in board.board().pieces() {
+        squares[sq as usize] = Some(p);
+    }
+    squares
+}
+
+/// Draws the board with White at the bottom, `cell` filling in the lines of each square
+fn grid<F>(out: &mut String, width: usize, lines: usize, cell: F)
+where
+    F: Fn(usize, usize) -> String,
+{
+    let border = format!(" +{}\n", format!("{}+", "-".repeat(width)).repeat(8));
+    out.push_str(&border);
+    for rank in (0..8).rev() {
+        for line in 0..lines {
+            out.push_str(" |");
+            for file in 0..8 {
+                write!(out, "{:^1$}|", cell(rank * 8 + file, line), width).unwrap();
+            }
+            if line == 0 {
+                write!(out, " {}", rank + 1).unwrap();
+            }
+            out.push('\n');
+        }
+        out.push_str(&border);
+    }
+    let files: String = "abcdefgh"
+        .chars()
+        .map(|file| format!("{:^1$} ", file, width))
+        .collect();
+    writeln!(out, "  {}", files.trim_end()).unwrap();
+}
+
+#[inline]
+fn pawns(value: i32) -> String {
+    format!("{:+.2}", value as f64 / 100.)
+}
+
+/// The board diagram, FEN, Zobrist key and checkers, as printed by `d`
+pub fn position_report(board: &Chess) -> String {
+    let pieces = squares(board);
+    let mut out = String::new();
+    grid(&mut out, 3, 1, |sq, _| {
+        pieces[sq].map_or(' ', |p| p.char()).to_string()
+    });
+
+    let checkers: Vec<String> = board
+        .checkers()
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect();
+    writeln!(out).unwrap();
+    writeln!(out, "Fen: {}", Fen::from_setup(board)).unwrap();
+    writeln!(out, "Key: {:016X}", zobrist::hash(board)).unwrap();
+    write!(out, "Checkers: {}", checkers.join(" ")).unwrap();
+    out
+}
+
+/// Everything the engine thinks of a position without searching, as printed by `eval`,
+/// with the NNUE part only when `network` is given. All scores are in pawns from White's side
+pub fn eval_report(board: &Chess, network: Option<&Network>) -> String {
+    let sign = board.turn() as i32 * 2 - 1;
+    let mut out = position_report(board);
+    out.push_str("\n\n");
+
+    match network {
+        Some(net) => {
+            let values = piece_values(net, board);
+            let mut cells = [None; 64];
+            for (sq, v) in values {
+                cells[sq] = Some(v * sign);
+            }
+            let pieces = squares(board);
+            out.push_str("NNUE derived piece values:\n");
+            grid(&mut out, 7, 2, |sq, line| match (line, pieces[sq]) {
+                (0, Some(p)) => p.char().to_string(),
+                (1, _) => cells[sq].map_or(String::new(), pawns),
+                _ => String::new(),
+            });
+
+            let (turn, codes, piece_squares) = decode_board(board);
+            let raw = net.evaluate(turn, &codes, &piece_squares);
+            writeln!(out).unwrap();
+            writeln!(out, "NNUE evaluation: {} (raw)", pawns(raw * sign)).unwrap();
+            writeln!(
+                out,
+                "Halfmove clock {}, scaled by {:.2}: {}",
+                board.halfmoves(),
+                halfmove_scale(board),
+                pawns(scale_halfmoves(raw, board) * sign)
+            )
+            .unwrap();
+        }
+        None => out.push_str("NNUE evaluation: not available\n"),
+    }
+
+    let e = evaluation(board, None, false);
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "Hand-crafted evaluation in centipawns, phase {} of {}:",
+        game_phase(board),
+        MAX_PHASE
+    )
+    .unwrap();
+    writeln!(out, "{}", e).unwrap();
+    write!(out, "Hand-crafted evaluation: {}", pawns(e.total())).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::{CastlingMode, FromSetup};
+    use crate::nnue::fixture;
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    #[test]
+    fn diagram() {
+        let report = position_report(&Chess::default());
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], " +---+---+---+---+---+---+---+---+");
+        assert_eq!(lines[1], " | r | n | b | q | k | b | n | r | 8");
+        assert_eq!(lines[15], " | R | N | B | Q | K | B | N | R | 1");
+        assert_eq!(lines[17], "   a   b   c   d   e   f   g   h");
+        assert!(report.contains("Fen: rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"));
+        assert!(report.ends_with("Checkers: "));
+    }
+
+    #[test]
+    fn key_and_checkers() {
+        let board = from_fen("4k3/8/8/8/8/8/4r3/4K3 w - - 0 1");
+        let report = position_report(&board);
+        assert!(report.contains(&format!("Key: {:016X}", zobrist::hash(&board))));
+        assert!(report.ends_with("Checkers: e2"));
+    }
+
+    #[test]
+    fn hand_crafted_terms() {
+        let board = from_fen("4k3/8/8/8/8/8/8/3QK3 b - - 0 1");
+        let report = eval_report(&board, None);
+        let total = evaluation(&board, None, false).total();
+        assert!(total > 0);
+        assert!(report.contains("phase 4 of 24"));
+        assert!(report.contains("Passed pawns"));
+        assert!(report.ends_with(&format!("Hand-crafted evaluation: {}", pawns(total))));
+    }
+
+    #[test]
+    fn without_network() {
+        let report = eval_report(&Chess::default(), None);
+        assert!(report.contains("NNUE evaluation: not available"));
+        assert!(!report.contains("NNUE derived piece values"));
+    }
+
+    #[test]
+    fn with_network() {
+        let net = fixture::network();
+        // Black to move, so every NNUE score is flipped to White's side
+        let board = from_fen("4k3/8/8/8/8/8/8/3QK3 b - - 20 60");
+        let report = eval_report(&board, Some(&net));
+
+        let values = piece_values(&net, &board);
+        assert_eq!(values.len(), 1);
+        let lines: Vec<&str> = report.lines().collect();
+        let queen = lines.iter().position(|x| x.contains("|   Q   |")).unwrap();
+        assert!(lines[queen].ends_with(" 1"));
+        assert!(lines[queen + 1].contains(&format!("|{:^7}|", pawns(-values[0].1))));
+
+        let (turn, pieces, squares) = decode_board(&board);
+        let raw = net.evaluate(turn, &pieces, &squares);
+        assert!(report.contains(&format!("NNUE evaluation: {} (raw)", pawns(-raw))));
+        assert!(report.contains(&format!(
+            "Halfmove clock 20, scaled by 0.80: {}",
+            pawns(-((raw as f32 * 0.8) as i32))
+        )));
+        assert!(report.contains("Hand-crafted evaluation in centipawns"));
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use array_macro::array;
 use lazy_static::lazy_static;
 
-use crate::chess::{CastlingMode, Chess, Move, Position, Setup};
-use crate::nnue::nnue_eval_normal;
-use crate::ordering::{MoveOrderer, OrderingHistory};
+use crate::chess::{CastlingMode, Chess, Move, MoveList, Position, Setup};
+use crate::nnue::{network, NnueState};
+use crate::options::EngineOptions;
+use crate::ordering::{Continuation, MoveOrderer, OrderingHistory, Stage};
+use crate::pruning::Pruning;
+use crate::see::see_ge;
+use crate::syzygy::{self, tablebase, wdl_score, Tables};
 use crate::timeman::*;
-use crate::tt::{TTEntry, TTFlag, TranspositionTable};
-use crate::weight::{fast_eval, fast_eval_endgame, is_checkmate, INF_SCORE};
+use crate::tt::{decode_move, score_from_tt, score_to_tt, TTEntry, TTFlag, TranspositionTable};
+use crate::weight::{
+    evaluate, evaluate_cached, is_checkmate, mate_distance, uci_score, PawnCache, INF_SCORE,
+};
+use crate::zobrist;
 
 pub type Depth = i8;
 pub type Ply = usize;
@@ -21,6 +28,7 @@ pub struct Statistics {
     pub beta_cutoffs: u64,
     pub qbeta_cutoffs: u64,
     pub tt_hits: u64,
+    pub tb_hits: u64,
     pub nodes: u64,
     pub qnodes: u64,
 }
@@ -31,23 +39,45 @@ impl Statistics {
     }
 }
 
-pub struct Search<'a> {
+/// A root move with its score and principal variation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootLine {
+    pub score: i32,
+    pub pv: Vec<Move>,
+}
+
+pub struct Search {
     pub stop: bool,
     pub sel_depth: Ply,
     pub timer: Timer,
-    pub tt: &'a mut TranspositionTable,
+    pub tt: Arc<TranspositionTable>,
     pub stats: Statistics,
     pub ordering_history: OrderingHistory,
     pub move_table: Vec<u64>,
     pub move_table_index_stack: Vec<usize>,
     pub pv_length: [u32; 64],
     pub pv_table: [[Option<Move>; 64]; 64],
+    pub nnue: NnueState,
+    pub thread_id: usize,
+    pub node_counts: Arc<Vec<AtomicU64>>,
+    pub ponder_move: Option<Move>,
+    pub multi_pv: usize,
+    pub excluded_root_moves: Vec<Move>,
+    pub search_moves: Vec<Move>,
+    pub root_lines: Vec<RootLine>,
+    pub mate_search: bool,
+    pub tb: Option<Arc<Tables>>,
+    pub pruning: Pruning,
+    pub pawn_cache: PawnCache,
+    /// Pawn keys along the current line, kept next to the NNUE stack
+    pub pawn_keys: Vec<u64>,
 }
 
-impl<'a> Search<'a> {
-    pub fn new(timer: Timer, tt: &'a mut TranspositionTable) -> Self {
+impl Search {
+    pub fn new(timer: Timer, tt: Arc<TranspositionTable>) -> Self {
         let mut is = Vec::with_capacity(80);
         is.push(0);
+        let mate_search = timer.mate_limit().is_some();
         Search {
             stop: false,
             sel_depth: 0,
@@ -59,23 +89,103 @@ impl<'a> Search<'a> {
             move_table_index_stack: is,
             pv_length: [0; 64],
             pv_table: array![array![None; 64]; 64],
+            nnue: NnueState::new(network()),
+            thread_id: 0,
+            node_counts: Arc::new(vec![AtomicU64::new(0)]),
+            ponder_move: None,
+            multi_pv: 1,
+            excluded_root_moves: Vec::new(),
+            search_moves: Vec::new(),
+            root_lines: Vec::new(),
+            mate_search,
+            tb: tablebase(),
+            pruning: Pruning::default(),
+            pawn_cache: PawnCache::new(),
+            pawn_keys: Vec::with_capacity(128),
         }
     }
 
+    pub fn set_options(&mut self, options: &EngineOptions) {
+        if !options.use_nnue {
+            self.nnue = NnueState::new(None);
+        }
+        self.multi_pv = options.multi_pv.max(1);
+        self.pruning = options.pruning;
+    }
+
+    #[inline]
+    pub fn is_main(&self) -> bool {
+        self.thread_id == 0
+    }
+
+    /// Legal moves at the root, restricted by `go searchmoves`
+    pub fn root_moves(&self, board: &Chess) -> MoveList {
+        let mut moves = board.legal_moves();
+        if !self.search_moves.is_empty() {
+            moves.retain(|m| self.search_moves.contains(m));
+        }
+        moves
+    }
+
+    /// Whether a mate score ends iterative deepening,
+    /// under `go mate` only a mate within the limit does
+    #[inline]
+    fn is_mate_final(&self, score: i32) -> bool {
+        match self.timer.mate_limit() {
+            Some(moves) => {
+                is_checkmate(score) && (1..=moves as i32).contains(&mate_distance(score))
+            }
+            None => is_checkmate(score),
+        }
+    }
+
+    /// Whether the root search only looks at some of the legal moves,
+    /// in which case its result must not go into the TT
+    #[inline]
+    fn is_root_restricted(&self) -> bool {
+        !self.excluded_root_moves.is_empty() || !self.search_moves.is_empty()
+    }
+
     pub fn mtdf(&mut self, board: &Chess) -> (Move, i32) {
         let mut alpha = -INF_SCORE;
         let mut beta = INF_SCORE;
-        let mut depth = 1;
+        // Lazy SMP: helpers start one ply deeper every other thread
+        let mut depth = 1 + (self.thread_id % 2) as Depth;
         let mut final_move = None;
         let mut final_score = 0;
         let mut last_score = 0;
 
-        let moves = board.legal_moves();
-        if moves.len() == 1 {
+        // keep only the moves that preserve the tablebase result
+        if let Some(tb_moves) = self.tb.as_ref().and_then(|x| syzygy::root_moves(x, board)) {
+            let allowed: Vec<Move> = tb_moves
+                .iter()
+                .filter(|m| self.search_moves.is_empty() || self.search_moves.contains(m))
+                .cloned()
+                .collect();
+            if !allowed.is_empty() {
+                self.search_moves = allowed;
+            }
+        }
+
+        let moves = self.root_moves(board);
+        // a single move left by searchmoves still gets searched for its score and pv
+        if board.legal_moves().len() == 1 {
             return (moves[0].clone(), 0);
         }
 
-        while !self.stop && self.timer.start_check(depth) && !is_checkmate(final_score) {
+        self.reset_stacks(board);
+
+        // only the main thread reports several lines, helpers just fill the TT
+        if self.multi_pv > 1 && self.is_main() {
+            let lines = self.multi_pv.min(moves.len());
+            return self.multi_pv_search(board, lines, moves[0].clone());
+        }
+
+        while !self.stop
+            && depth <= Self::MAX_DEPTH
+            && self.timer.start_check(depth)
+            && !self.is_mate_final(final_score)
+        {
             if board.is_game_over() {
                 break;
             }
@@ -101,19 +211,79 @@ impl<'a> Search<'a> {
             } else {
                 if let Some(res) = self.print_info(depth, cur_move, final_score) {
                     final_move = Some(res);
+                    self.ponder_move = if self.pv_length[0] > 1 {
+                        self.pv_table[0][1].clone()
+                    } else {
+                        None
+                    };
                 }
 
                 alpha = final_score - Self::ASPIRATION_WINDOW;
                 beta = final_score + Self::ASPIRATION_WINDOW;
                 depth += 1;
-                self.stats = Statistics::default();
             }
 
-            if is_checkmate(final_score) {
+            if self.is_mate_final(final_score) {
                 break;
             }
         }
-        (final_move.unwrap(), final_score)
+        (final_move.unwrap_or_else(|| moves[0].clone()), final_score)
+    }
+
+    /// Iterative deepening over the `lines` best root moves,
+    /// each line is searched with a full window and the root moves of the lines above excluded
+    fn multi_pv_search(&mut self, board: &Chess, lines: usize, fallback: Move) -> (Move, i32) {
+        let mut depth = 1;
+
+        while !self.stop && depth <= Self::MAX_DEPTH && self.timer.start_check(depth) {
+            let mut found = Vec::with_capacity(lines);
+            self.excluded_root_moves.clear();
+
+            for _ in 0..lines {
+                let (m, score) = self.negamax_root(board, depth, -INF_SCORE, INF_SCORE);
+                if self.stop {
+                    break;
+                }
+                let mut pv: Vec<Move> = self.pv_table[0][..self.pv_length[0] as usize]
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .collect();
+                if pv.is_empty() {
+                    pv.push(m.clone());
+                }
+                self.excluded_root_moves.push(m);
+                found.push(RootLine { score, pv });
+            }
+            self.excluded_root_moves.clear();
+
+            if self.stop {
+                break;
+            }
+
+            // stable, so lines with equal scores keep the order they were found in
+            found.sort_by(|a, b| b.score.cmp(&a.score));
+
+            if depth >= 4 && !self.root_lines.is_empty() {
+                self.timer.update(found[0].score - self.root_lines[0].score);
+            }
+
+            self.root_lines = found;
+            self.print_lines(depth);
+            depth += 1;
+
+            if self.is_mate_final(self.root_lines[0].score) {
+                break;
+            }
+        }
+
+        match self.root_lines.first() {
+            Some(line) => {
+                self.ponder_move = line.pv.get(1).cloned();
+                (line.pv[0].clone(), line.score)
+            }
+            None => (fallback, 0),
+        }
     }
 
     pub fn negamax_root(
@@ -127,69 +297,70 @@ impl<'a> Search<'a> {
 
         self.pv_length[ply] = ply as u32;
 
-        let moves = board.legal_moves();
+        let moves = self.root_moves(board);
 
         let in_check = board.is_check();
         if in_check {
             depth += 1;
         }
 
-        let first_move = moves[0].clone();
+        let first_move = moves
+            .iter()
+            .find(|m| !self.excluded_root_moves.contains(m))
+            .unwrap_or(&moves[0])
+            .clone();
 
         let mut best_move = first_move;
-        if moves.len() == 1 {
-            return (best_move, 0);
-        }
 
-        let mut hasher = DefaultHasher::new();
-        board.board().hash(&mut hasher);
-        let hs = hasher.finish();
+        let hs = zobrist::hash(board);
 
         let mut hash_move = None;
         if let Some(ttentry) = self.tt.get(hs) {
-            hash_move = ttentry.bestmove.clone();
+            hash_move = decode_move(board, ttentry.bestmove);
         }
 
         let mut value;
-        let mut orderer = MoveOrderer::new(moves);
-        while let Some(m) = orderer.next_move(&self.ordering_history, &hash_move, board, 0) {
+        let mut tt_flag = TTFlag::Upper;
+        let mut orderer = MoveOrderer::with_moves(moves, hash_move);
+        let mut quiets = MoveList::new();
+        while let Some(m) = orderer.next_move(&self.ordering_history, board, 0) {
+            if self.excluded_root_moves.contains(&m) {
+                continue;
+            }
+
             let mut nb = board.clone();
             nb.play_unchecked(&m);
+            self.push_move(board, &m, &nb);
 
-            let mut hasher = DefaultHasher::new();
-            nb.board().hash(&mut hasher);
-            let nhs = hasher.finish();
+            let nhs = zobrist::update(hs, board, &m, &nb);
 
             self.move_table.push(nhs);
             if m.is_zeroing() {
                 self.move_table_index_stack.push(self.move_table.len() - 1);
             }
 
-            value = -self.negamax(&mut nb, depth - 1, 1, -beta, -alpha, true);
+            let cont = [Some(m.clone()), None];
+            value = -self.negamax(&nb, nhs, depth - 1, 1, -beta, -alpha, true, &cont);
 
+            self.pop_move();
             self.move_table.pop();
             if m.is_zeroing() {
                 self.move_table_index_stack.pop();
             }
 
-            if self.stop || self.timer.stop_check() {
-                self.stop = true;
+            if self.check_stop() {
                 break;
             }
 
             if value >= beta {
-                self.tt.insert(
-                    hs,
-                    TTEntry::construct(hs, beta, Some(best_move.clone()), depth, TTFlag::Lower),
-                );
-
                 if !m.is_capture() && !m.is_promotion() {
-                    self.ordering_history.add_killer(board, m.clone(), ply);
-                    self.ordering_history.add_history(&m, depth);
+                    self.update_quiet_history(board, &m, &quiets, depth, ply, &[None, None]);
                 }
                 self.stats.beta_cutoffs += 1;
-
-                return (best_move.clone(), beta);
+                best_move = m;
+                tt_flag = TTFlag::Lower;
+                alpha = beta;
+                break;
             }
 
             // found a better move
@@ -209,38 +380,48 @@ impl<'a> Search<'a> {
                 // adjust pv length
                 self.pv_length[ply] = self.pv_length[ply + 1];
 
-                self.tt.insert(
-                    hs,
-                    TTEntry::construct(hs, alpha, Some(best_move.clone()), depth, TTFlag::Upper),
-                );
+                tt_flag = TTFlag::Exact;
+            }
+
+            if !m.is_capture() && !m.is_promotion() {
+                quiets.push(m);
             }
         }
 
-        if !self.stop {
+        // a search over some of the moves says nothing about the position itself
+        if !self.stop && !self.is_root_restricted() {
             self.tt.insert(
                 hs,
-                TTEntry::construct(hs, alpha, Some(best_move.clone()), depth, TTFlag::Exact),
+                TTEntry::construct(hs, alpha, Some(best_move.clone()), depth, tt_flag),
             );
         }
-        (best_move.clone(), alpha)
+        (best_move, alpha)
     }
 
-    /// core negamax search
+    /// core negamax search, `cont` holds the moves that led to the node
+    #[allow(clippy::too_many_arguments)]
     pub fn negamax(
         &mut self,
         board: &Chess,
+        hs: u64,
         mut depth: Depth,
         ply: Ply,
         mut alpha: i32,
         mut beta: i32,
         can_apply_null: bool,
+        cont: &Continuation,
     ) -> i32 {
-        if self.stop || self.timer.stop_check() {
-            self.stop = true;
+        if self.check_stop() {
             return 0;
         }
 
+        if ply >= Self::MAX_PLY {
+            return self.static_eval(board);
+        }
+
         self.pv_length[ply] = ply as u32;
+        // taken from the window we were called with, before the TT narrows it
+        let pv_node = beta > alpha + 1;
 
         // Mate distance pruning
         // https://www.chessprogramming.org/Mate_Distance_Pruning
@@ -267,15 +448,11 @@ impl<'a> Search<'a> {
         // Quiescence search
         // when depth is zero, we go for captures
         if depth <= 0 && !in_check {
-            return self.q_search(board, ply, alpha, beta);
+            return self.q_search(board, hs, ply, alpha, beta);
         }
 
         self.stats.nodes += 1;
 
-        let mut hasher = DefaultHasher::new();
-        board.board().hash(&mut hasher);
-        let hs = hasher.finish();
-
         // Fifty-move rule and Repetitions
         if board.halfmoves() > 6 {
             if board.halfmoves() >= 100 || self.is_repetition(hs) {
@@ -289,35 +466,87 @@ impl<'a> Search<'a> {
         if let Some(tt_entry) = self.tt.get(hs) {
             if tt_entry.depth >= depth {
                 self.stats.tt_hits += 1;
+                let tt_score = score_from_tt(tt_entry.score, ply);
                 match tt_entry.flag {
                     TTFlag::Exact => {
                         self.stats.leafs += 1;
-                        return tt_entry.score;
+                        return tt_score;
                     }
                     TTFlag::Lower => {
-                        alpha = alpha.max(tt_entry.score);
+                        alpha = alpha.max(tt_score);
                     }
                     TTFlag::Upper => {
-                        beta = beta.max(tt_entry.score);
+                        beta = beta.min(tt_score);
                     }
                     _ => {}
                 }
                 if alpha >= beta {
                     self.stats.leafs += 1;
                     self.stats.beta_cutoffs += 1;
-                    return tt_entry.score;
+                    return tt_score;
                 }
             }
-            hash_move = tt_entry.bestmove.clone();
+            hash_move = decode_move(board, tt_entry.bestmove);
         }
 
+        // Tablebases, probed right after captures and pawn moves
+        // when the fifty-move counter does not distort the result
+        if board.halfmoves() == 0 {
+            if let Some(wdl) = self.tb.as_ref().and_then(|x| syzygy::probe_wdl(x, board)) {
+                self.stats.tb_hits += 1;
+                return wdl_score(wdl, ply);
+            }
+        }
+
+        // Forward pruning on the static evaluation, never in a mate search,
+        // at PV nodes, in check or with mate scores around
+        let can_prune = !self.mate_search
+            && !pv_node
+            && !in_check
+            && !is_checkmate(alpha)
+            && !is_checkmate(beta);
+        let static_eval = if can_prune {
+            self.static_eval(board)
+        } else {
+            0
+        };
+
+        if can_prune {
+            if self.pruning.reverse_futility(depth, static_eval, beta) {
+                self.stats.beta_cutoffs += 1;
+                return beta;
+            }
+
+            if self.pruning.razor(depth, static_eval, alpha) {
+                let value = self.q_search(board, hs, ply, alpha, alpha + 1);
+                if self.stop {
+                    return 0;
+                }
+                if value <= alpha {
+                    return alpha;
+                }
+            }
+        }
+        let futile = can_prune && self.pruning.futility(depth, static_eval, alpha);
+
         // Null Move Pruning
         // Borrowed from https://github.com/Heiaha/Weiawaga/blob/92d1c9b7f78ec6a6f8762d8719fa7e8a871cf8df/src/search/search.rs#L224
-        if Self::can_apply_null(board, depth, beta, in_check, can_apply_null) {
+        // a mate search has to see every line, so nothing is pruned or reduced
+        if !self.mate_search && Self::can_apply_null(board, depth, beta, in_check, can_apply_null) {
             let r = if depth > 6 { 3 } else { 2 };
 
             let nb = board.clone().swap_turn().unwrap();
-            let value = -self.negamax(&nb, depth - r - 1, ply, -beta, -beta + 1, false);
+            let nhs = zobrist::null_move(hs, board, &nb);
+            let value = -self.negamax(
+                &nb,
+                nhs,
+                depth - r - 1,
+                ply,
+                -beta,
+                -beta + 1,
+                false,
+                &[None, cont[0].clone()],
+            );
             if self.stop {
                 return 0;
             }
@@ -331,40 +560,69 @@ impl<'a> Search<'a> {
         let mut reduced_depth: Depth;
         let mut best_move: Option<Move> = None;
         let mut tt_flag = TTFlag::Upper;
-        let moves = board.legal_moves();
-        let lmoves = moves.len();
-        let mut orderer = MoveOrderer::new(moves);
+        let mut orderer = MoveOrderer::new(hash_move, cont.clone());
+        let mut quiets = MoveList::new();
 
         let mut pidx = 0;
 
-        while let Some(m) = orderer.next_move(&self.ordering_history, &hash_move, board, ply) {
+        while let Some(m) = orderer.next_move(&self.ordering_history, board, ply) {
             let mut nb = board.clone();
             nb.play_unchecked(&m);
 
-            let mut hasher = DefaultHasher::new();
-            nb.board().hash(&mut hasher);
-            let nhs = hasher.finish();
+            // quiet moves that do not give check, the first move is always searched
+            if pidx > 0
+                && can_prune
+                && !m.is_capture()
+                && !m.is_promotion()
+                && !nb.is_check()
+                && (futile || self.pruning.late_move(depth, pidx))
+            {
+                continue;
+            }
+
+            self.push_move(board, &m, &nb);
+
+            let nhs = zobrist::update(hs, board, &m, &nb);
             self.move_table.push(nhs);
             if m.is_zeroing() {
                 self.move_table_index_stack.push(self.move_table.len());
             }
 
+            let next = [Some(m.clone()), cont[0].clone()];
+
             // PVS
             if pidx == 0 {
-                value = -self.negamax(&nb, depth - 1, ply + 1, -beta, -alpha, true);
+                value = -self.negamax(&nb, nhs, depth - 1, ply + 1, -beta, -alpha, true, &next);
             } else {
                 reduced_depth = depth;
 
                 // LMR
-                if Self::can_apply_lmr(&m, depth, pidx) {
+                if !self.mate_search && Self::can_apply_lmr(board, &m, depth, pidx) {
                     reduced_depth -= Self::late_move_reduction(depth, pidx);
                 }
 
                 loop {
-                    value =
-                        -self.negamax(&nb, reduced_depth - 1, ply + 1, -alpha - 1, -alpha, true);
+                    value = -self.negamax(
+                        &nb,
+                        nhs,
+                        reduced_depth - 1,
+                        ply + 1,
+                        -alpha - 1,
+                        -alpha,
+                        true,
+                        &next,
+                    );
                     if value > alpha {
-                        value = -self.negamax(&nb, reduced_depth - 1, ply + 1, -beta, -alpha, true);
+                        value = -self.negamax(
+                            &nb,
+                            nhs,
+                            reduced_depth - 1,
+                            ply + 1,
+                            -beta,
+                            -alpha,
+                            true,
+                            &next,
+                        );
                     }
 
                     if reduced_depth != depth && value > alpha {
@@ -375,6 +633,7 @@ impl<'a> Search<'a> {
                 }
             }
 
+            self.pop_move();
             self.move_table.pop();
             if m.is_zeroing() {
                 self.move_table_index_stack.pop();
@@ -387,10 +646,10 @@ impl<'a> Search<'a> {
             // fail hard beta cutoff
             if value >= beta {
                 if !m.is_capture() && !m.is_promotion() {
-                    self.ordering_history.add_killer(board, m.clone(), ply);
-                    self.ordering_history.add_history(&m, depth);
+                    self.update_quiet_history(board, &m, &quiets, depth, ply, cont);
                 }
                 self.stats.beta_cutoffs += 1;
+                best_move = Some(m);
                 tt_flag = TTFlag::Lower;
                 alpha = beta;
                 break;
@@ -415,10 +674,14 @@ impl<'a> Search<'a> {
                 alpha = value;
             }
 
+            if !m.is_capture() && !m.is_promotion() {
+                quiets.push(m);
+            }
             pidx += 1;
         }
 
-        if lmoves == 0 {
+        // the picker ran dry without yielding a single move
+        if pidx == 0 && orderer.stage == Stage::Done {
             if in_check {
                 alpha = -mate_value;
             } else {
@@ -427,55 +690,59 @@ impl<'a> Search<'a> {
         }
 
         if !self.stop {
-            self.tt
-                .insert(hs, TTEntry::construct(hs, alpha, best_move, depth, tt_flag));
+            self.tt.insert(
+                hs,
+                TTEntry::construct(hs, score_to_tt(alpha, ply), best_move, depth, tt_flag),
+            );
         }
         alpha
     }
 
-    pub fn q_search(&mut self, board: &Chess, ply: Ply, mut alpha: i32, beta: i32) -> i32 {
-        if self.stop || self.timer.stop_check() {
-            self.stop = true;
+    pub fn q_search(&mut self, board: &Chess, hs: u64, ply: Ply, mut alpha: i32, beta: i32) -> i32 {
+        if self.check_stop() {
             return 0;
         }
 
         self.sel_depth = self.sel_depth.max(ply);
         self.stats.qnodes += 1;
 
-        let value = if board.fullmoves().get() >= 100 {
-            fast_eval_endgame(board)
-        } else {
-            nnue_eval_normal(board)
-        };
+        let stand_pat = self.static_eval(board);
 
-        if value >= beta {
+        if stand_pat >= beta {
             self.stats.qleafs += 1;
             return beta;
         }
 
-        if alpha < value {
-            alpha = value;
+        if alpha < stand_pat {
+            alpha = stand_pat;
         }
 
-        let mut hasher = DefaultHasher::new();
-        board.board().hash(&mut hasher);
-        let hs = hasher.finish();
-
         let mut hash_move = None;
         if let Some(ttentry) = self.tt.get(hs) {
-            hash_move = ttentry.bestmove.clone();
+            hash_move = decode_move(board, ttentry.bestmove);
         }
 
         let mut value;
 
-        let moves = board.capture_moves();
-        let mut orderer = MoveOrderer::new(moves);
+        let mut orderer = MoveOrderer::captures(hash_move);
+
+        while let Some(m) = orderer.next_move(&self.ordering_history, board, ply) {
+            // captures losing material cannot raise alpha over the stand pat
+            if !see_ge(board, &m, 0)
+                || (!self.mate_search && self.pruning.delta(stand_pat, &m, alpha))
+            {
+                continue;
+            }
 
-        while let Some(m) = orderer.next_move(&self.ordering_history, &hash_move, board, ply) {
             let mut nb = board.clone();
             nb.play_unchecked(&m);
+            self.push_move(board, &m, &nb);
+
+            let nhs = zobrist::update(hs, board, &m, &nb);
 
-            value = -self.q_search(&nb, ply + 1, -beta, -alpha);
+            value = -self.q_search(&nb, nhs, ply + 1, -beta, -alpha);
+
+            self.pop_move();
 
             if self.stop {
                 return 0;
@@ -492,6 +759,86 @@ impl<'a> Search<'a> {
         alpha
     }
 
+    /// The quiet `m` caused a cutoff: it becomes the killer and the countermove,
+    /// its history grows and that of the quiets searched before it shrinks
+    fn update_quiet_history(
+        &mut self,
+        board: &Chess,
+        m: &Move,
+        quiets: &[Move],
+        depth: Depth,
+        ply: Ply,
+        cont: &Continuation,
+    ) {
+        let oh = &mut self.ordering_history;
+        oh.add_killer(board, m.clone(), ply);
+        oh.add_counter_move(cont, m.clone());
+        oh.add_history(m, depth, cont);
+        for quiet in quiets {
+            oh.add_malus(quiet, depth, cont);
+        }
+    }
+
+    /// Starts the NNUE and pawn key stacks over at `board`
+    pub fn reset_stacks(&mut self, board: &Chess) {
+        self.nnue.reset(board);
+        self.pawn_keys.clear();
+        self.pawn_keys.push(zobrist::pawn_hash(board));
+    }
+
+    /// Follows `m` from `board` to `after` on both stacks
+    #[inline]
+    fn push_move(&mut self, board: &Chess, m: &Move, after: &Chess) {
+        self.nnue.push(board, m, after);
+        let key = *self.pawn_keys.last().unwrap();
+        self.pawn_keys.push(zobrist::update_pawns(key, board, m));
+    }
+
+    #[inline]
+    fn pop_move(&mut self) {
+        self.nnue.pop();
+        self.pawn_keys.pop();
+    }
+
+    #[inline]
+    fn static_eval(&mut self, board: &Chess) -> i32 {
+        if self.nnue.is_active() {
+            self.nnue.evaluate(board)
+        } else {
+            let key = *self.pawn_keys.last().unwrap();
+            evaluate_cached(board, key, &mut self.pawn_cache)
+        }
+    }
+
+    /// Polls the timer and publishes the node count for the other threads
+    #[inline]
+    fn check_stop(&mut self) -> bool {
+        let nodes = self.stats.total_nodes();
+        if nodes & 1023 == 0 {
+            self.node_counts[self.thread_id].store(nodes, Ordering::Relaxed);
+        }
+        // go nodes limits the nodes of all threads together
+        if self.stop || self.timer.stop_check(self.total_nodes()) {
+            self.stop = true;
+        }
+        self.stop
+    }
+
+    /// Nodes searched by all threads
+    pub fn total_nodes(&self) -> u64 {
+        self.node_counts
+            .iter()
+            .enumerate()
+            .map(|(id, x)| {
+                if id == self.thread_id {
+                    self.stats.total_nodes()
+                } else {
+                    x.load(Ordering::Relaxed)
+                }
+            })
+            .sum()
+    }
+
     #[inline]
     fn is_repetition(&self, position: u64) -> bool {
         self.move_table
@@ -515,15 +862,16 @@ impl<'a> Search<'a> {
             && !in_check
             && depth >= Self::NULL_MIN_DEPTH
             && (mt.count() - mt.pawns as usize) > 0
-            && fast_eval(board) >= beta
+            && evaluate(board) >= beta
     }
 
     #[inline]
-    fn can_apply_lmr(m: &Move, depth: Depth, move_index: usize) -> bool {
+    fn can_apply_lmr(board: &Chess, m: &Move, depth: Depth, move_index: usize) -> bool {
+        // captures that lose material are reduced like quiet moves
         depth >= Self::LMR_MIN_DEPTH
             && move_index >= Self::LMR_MOVE_WO_REDUCTION
             && !m.is_promotion()
-            && !m.is_capture()
+            && (!m.is_capture() || !see_ge(board, m, 0))
     }
 
     #[inline]
@@ -553,22 +901,59 @@ impl<'a> Search<'a> {
 
     pub fn print_info(&self, depth: Depth, m: Move, score: i32) -> Option<Move> {
         let (pv, bestmove) = self.get_pv();
+        if !self.is_main() {
+            return bestmove;
+        }
+        let nodes = self.total_nodes();
         println!(
-            "info currmove {} depth {} seldepth {} time {} score cp {} nodes {} nps {} pv {}",
+            "info currmove {} depth {} seldepth {} time {} score {} nodes {} nps {} hashfull {} pv {}",
             crate::chess::uci::Uci::from_standard(&m).to_string(),
             depth,
             self.sel_depth,
             self.timer.elapsed(),
-            score,
-            self.stats.total_nodes(),
-            1000 * self.stats.total_nodes() / (self.timer.elapsed() + 1),
+            uci_score(score),
+            nodes,
+            1000 * nodes / (self.timer.elapsed() + 1),
+            self.tt.hashfull(),
             pv
         );
         bestmove
     }
 
+    /// Prints one `info multipv` line per root line, best first
+    pub fn print_lines(&self, depth: Depth) {
+        if !self.is_main() {
+            return;
+        }
+        let nodes = self.total_nodes();
+        let elapsed = self.timer.elapsed();
+        let hashfull = self.tt.hashfull();
+        for (idx, line) in self.root_lines.iter().enumerate() {
+            let pv: Vec<String> = line
+                .pv
+                .iter()
+                .map(|m| m.to_uci(CastlingMode::Standard).to_string())
+                .collect();
+            println!(
+                "info depth {} seldepth {} multipv {} score {} nodes {} nps {} hashfull {} time {} pv {}",
+                depth,
+                self.sel_depth,
+                idx + 1,
+                uci_score(line.score),
+                nodes,
+                1000 * nodes / (elapsed + 1),
+                hashfull,
+                elapsed,
+                pv.join(" ")
+            );
+        }
+    }
+
     // constants
     pub const NULL_MIN_DEPTH: Depth = 2;
+    const MAX_DEPTH: Depth = 48;
+    /// Deepest ply the pv table has room for, check extensions can reach it at any depth
+    pub const MAX_PLY: Ply = 63;
     const ASPIRATION_WINDOW: i32 = 25;
 
     const LMR_MOVE_WO_REDUCTION: usize = 2;
@@ -595,7 +980,301 @@ fn init_lmr_table() -> [[Depth; 64]; 64] {
 
 #[cfg(test)]
 mod tests {
-    use crate::weight::{is_checkmate, INF_SCORE};
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::FromSetup;
+    use crate::weight::INF_SCORE;
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    fn searcher(board: &Chess, control: TimeControl, multi_pv: usize) -> Search {
+        let stop = Arc::new(AtomicBool::new(false));
+        let timer = Timer::new(board, control, stop);
+        let mut search = Search::new(timer, Arc::new(TranspositionTable::with_size(1)));
+        search.set_options(&EngineOptions {
+            use_nnue: false,
+            multi_pv,
+            ..EngineOptions::default()
+        });
+        search
+    }
+
+    #[test]
+    fn multi_pv_lines() {
+        let board = Chess::default();
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 3);
+        let (best, score) = search.mtdf(&board);
+
+        assert_eq!(search.root_lines.len(), 3);
+        assert_eq!(search.root_lines[0].pv[0], best);
+        assert_eq!(search.root_lines[0].score, score);
+        for pair in search.root_lines.windows(2) {
+            assert!(pair[0].score >= pair[1].score);
+            assert_ne!(pair[0].pv[0], pair[1].pv[0]);
+        }
+        assert_ne!(search.root_lines[0].pv[0], search.root_lines[2].pv[0]);
+    }
+
+    #[test]
+    fn searchmoves_with_multi_pv() {
+        use crate::timeman::parse_searchmoves;
+
+        let board = Chess::default();
+        let mut search = searcher(&board, TimeControl::FixedDepth(3), 3);
+        search.search_moves = parse_searchmoves("searchmoves a2a3 h2h3", &board).0;
+        let (best, _) = search.mtdf(&board);
+
+        assert!(search.search_moves.contains(&best));
+        assert_eq!(search.root_lines.len(), 2);
+        for line in search.root_lines.iter() {
+            assert!(search.search_moves.contains(&line.pv[0]));
+        }
+
+        // a single allowed move is still searched
+        let mut search = searcher(&board, TimeControl::FixedDepth(3), 1);
+        search.search_moves = parse_searchmoves("searchmoves g1f3", &board).0;
+        let (best, _) = search.mtdf(&board);
+        assert_eq!(best, search.search_moves[0]);
+        assert!(search.ponder_move.is_some());
+
+        // without a single legal entry every move is searched and a bestmove still comes out
+        let mut search = searcher(&board, TimeControl::FixedDepth(3), 1);
+        search.search_moves = parse_searchmoves("searchmoves e2e5 g1g3", &board).0;
+        let (best, _) = search.mtdf(&board);
+        assert!(board.is_legal(&best));
+        assert_eq!(search.root_moves(&board).len(), 20);
+    }
+
+    #[test]
+    fn multi_pv_more_than_legal_moves() {
+        // three legal king moves
+        let board = from_fen("7k/8/8/8/8/8/8/K7 w - - 0 1");
+        let mut search = searcher(&board, TimeControl::FixedDepth(3), 10);
+        search.mtdf(&board);
+        assert_eq!(search.root_lines.len(), 3);
+    }
+
+    #[test]
+    fn mate_puzzles() {
+        let puzzles = [
+            ("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", 1),
+            (
+                "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5Q2/PPPP1PPP/RNB1K1NR w KQkq - 0 1",
+                1,
+            ),
+            ("r5k1/8/8/8/8/8/5PPP/6K1 b - - 0 1", 1),
+            ("k7/8/2K5/8/8/8/8/7R w - - 0 1", 2),
+            ("6k1/pp4p1/2p5/2bp4/8/P5Pb/1P3rrP/2BRRN1K b - - 0 1", 2),
+            ("k7/8/8/3K4/8/8/8/6Q1 w - - 0 1", 3),
+            ("7k/8/8/4K3/8/8/8/R7 w - - 0 1", 3),
+        ];
+        for (fen, moves) in puzzles.iter() {
+            let board = from_fen(fen);
+            let mut search = searcher(&board, TimeControl::Mate(*moves), 1);
+            let (_, score) = search.mtdf(&board);
+            assert!(is_checkmate(score), "{}", fen);
+            assert_eq!(mate_distance(score), *moves as i32, "{}", fen);
+            assert_eq!(uci_score(score), format!("mate {}", moves));
+        }
+    }
+
+    #[test]
+    fn mate_refuted() {
+        // a mate in 2 is not a mate in 1
+        let board = from_fen("k7/8/2K5/8/8/8/8/7R w - - 0 1");
+        let mut search = searcher(&board, TimeControl::Mate(1), 1);
+        let (_, score) = search.mtdf(&board);
+        assert!(!is_checkmate(score));
+    }
+
+    #[test]
+    fn mate_length_through_tt() {
+        // the TT filled while finding the mate in 3 is reused one ply later,
+        // its mate scores were stored at other plies than they are probed at,
+        // Black has to have a choice for the second search to run at all
+        let board = from_fen("7k/8/8/4K3/8/8/8/R7 w - - 0 1");
+        let tt = Arc::new(TranspositionTable::with_size(1));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let timer = Timer::new(&board, TimeControl::Mate(3), stop.clone());
+        let mut search = Search::new(timer, tt.clone());
+        search.set_options(&EngineOptions {
+            use_nnue: false,
+            ..EngineOptions::default()
+        });
+        let (m, score) = search.mtdf(&board);
+        assert_eq!(uci_score(score), "mate 3");
+
+        let mut nb = board.clone();
+        nb.play_unchecked(&m);
+        let timer = Timer::new(&nb, TimeControl::FixedDepth(4), stop);
+        let mut search = Search::new(timer, tt);
+        search.set_options(&EngineOptions {
+            use_nnue: false,
+            ..EngineOptions::default()
+        });
+        search.mate_search = true;
+        let (_, score) = search.mtdf(&nb);
+        assert_eq!(uci_score(score), "mate -2");
+
+        let mut fresh = searcher(&nb, TimeControl::FixedDepth(4), 1);
+        fresh.mate_search = true;
+        assert_eq!(fresh.mtdf(&nb).1, score);
+    }
+
+    #[test]
+    fn upper_bound_fails_low() {
+        let board = from_fen("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3");
+        let hs = zobrist::hash(&board);
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        search
+            .tt
+            .insert(hs, TTEntry::construct(hs, -50, None, 10, TTFlag::Upper));
+
+        // the position is known to be worth at most -50, below the window
+        search.reset_stacks(&board);
+        let score = search.negamax(&board, hs, 3, 1, -20, 20, true, &[None, None]);
+        assert_eq!(score, -50);
+        assert_eq!(search.stats.nodes, 1);
+        assert_eq!(search.stats.beta_cutoffs, 1);
+    }
+
+    #[test]
+    fn node_limit_counts_all_threads() {
+        let board = Chess::default();
+        let mut search = searcher(&board, TimeControl::FixedNodes(20000), 1);
+        search.mtdf(&board);
+        let alone = search.stats.total_nodes();
+        assert!((20000..20100).contains(&alone), "{}", alone);
+
+        // a helper already searched 15000 of them
+        let mut search = searcher(&board, TimeControl::FixedNodes(20000), 1);
+        search.node_counts = Arc::new(vec![AtomicU64::new(0), AtomicU64::new(15000)]);
+        search.mtdf(&board);
+        let own = search.stats.total_nodes();
+        assert!((5000..5100).contains(&own), "{}", own);
+    }
+
+    #[test]
+    fn deepest_ply() {
+        // as if check extensions had taken the search this deep
+        let board = from_fen("7k/8/8/8/8/8/1R6/K5Q1 w - - 0 1");
+        let hs = zobrist::hash(&board);
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        search.reset_stacks(&board);
+        let score = search.negamax(&board, hs, 4, Search::MAX_PLY, -20, 20, true, &[None, None]);
+        assert_eq!(score, evaluate(&board));
+        assert_eq!(search.stats.nodes, 0);
+
+        // lines searched a few plies above it are cut there
+        search.negamax(
+            &board,
+            hs,
+            6,
+            Search::MAX_PLY - 3,
+            -INF_SCORE,
+            INF_SCORE,
+            true,
+            &[None, None],
+        );
+        assert!(search.stats.nodes > 0);
+    }
+
+    #[test]
+    fn root_entry_bounds() {
+        let board = from_fen("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3");
+        let hs = zobrist::hash(&board);
+        let probe = |search: &Search| {
+            let entry = search.tt.get(hs).unwrap();
+            (entry.flag, entry.score, decode_move(&board, entry.bestmove))
+        };
+
+        // inside the window the score is exact
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        search.reset_stacks(&board);
+        let (m, score) = search.negamax_root(&board, 3, -INF_SCORE, INF_SCORE);
+        assert_eq!(probe(&search), (TTFlag::Exact, score, Some(m)));
+
+        // a fail high is a lower bound on the move that caused it
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        search.reset_stacks(&board);
+        let (m, score) = search.negamax_root(&board, 3, -INF_SCORE, -1000);
+        assert_eq!(score, -1000);
+        assert_eq!(probe(&search), (TTFlag::Lower, -1000, Some(m)));
+
+        // a fail low is an upper bound
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        search.reset_stacks(&board);
+        let (_, score) = search.negamax_root(&board, 3, 1000, INF_SCORE);
+        assert_eq!(score, 1000);
+        assert_eq!(probe(&search).0, TTFlag::Upper);
+        assert_eq!(probe(&search).1, 1000);
+    }
+
+    #[test]
+    fn pv_node_from_original_window() {
+        let board = from_fen("4k3/8/8/8/8/8/8/3QK3 w - - 0 1");
+        let hs = zobrist::hash(&board);
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        search
+            .tt
+            .insert(hs, TTEntry::construct(hs, 19, None, 10, TTFlag::Lower));
+
+        // the entry narrows the window to a null one, the node is still searched as PV
+        search.reset_stacks(&board);
+        let score = search.negamax(&board, hs, 2, 1, -20, 20, false, &[None, None]);
+        assert!(score >= 20);
+        assert!(search.stats.nodes > 1);
+    }
+
+    #[test]
+    fn tactics_with_pruning() {
+        let mates = [
+            ("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", 1),
+            ("r5k1/8/8/8/8/8/5PPP/6K1 b - - 0 1", 1),
+            ("k7/8/2K5/8/8/8/8/7R w - - 0 1", 2),
+            ("6k1/pp4p1/2p5/2bp4/8/P5Pb/1P3rrP/2BRRN1K b - - 0 1", 2),
+        ];
+        let off = Pruning {
+            rfp: false,
+            futility: false,
+            razoring: false,
+            lmp: false,
+            delta: false,
+            ..Pruning::default()
+        };
+
+        for pruning in [Pruning::default(), off].iter() {
+            for (fen, moves) in mates.iter() {
+                let board = from_fen(fen);
+                let mut search = searcher(&board, TimeControl::FixedDepth(5), 1);
+                search.pruning = *pruning;
+                let (_, score) = search.mtdf(&board);
+                assert_eq!(uci_score(score), format!("mate {}", moves), "{}", fen);
+            }
+
+            // the hanging queen is taken
+            let board = from_fen("4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1");
+            let mut search = searcher(&board, TimeControl::FixedDepth(5), 1);
+            search.pruning = *pruning;
+            let (m, _) = search.mtdf(&board);
+            assert_eq!(m.to_uci(CastlingMode::Standard).to_string(), "d2d5");
+        }
+    }
+
+    #[test]
+    fn getting_mated() {
+        let board = from_fen("k7/8/1K6/8/8/p7/8/7R b - - 0 1");
+        let mut search = searcher(&board, TimeControl::FixedDepth(4), 1);
+        let (_, score) = search.mtdf(&board);
+        assert_eq!(uci_score(score), "mate -1");
+    }
 
     #[test]
     fn is_mate() {
@@ -604,5 +1283,10 @@ mod tests {
 
         let score = INF_SCORE - 20;
         assert!(is_checkmate(score));
+
+        assert_eq!(mate_distance(INF_SCORE - 1), 1);
+        assert_eq!(mate_distance(INF_SCORE - 5), 3);
+        assert_eq!(mate_distance(-(INF_SCORE - 2)), -1);
+        assert_eq!(mate_distance(-(INF_SCORE - 4)), -2);
     }
 }
@@ -0,0 +1,148 @@
+use crate::chess::{attacks, Bitboard, Board, Chess, Color, Move, Role, Setup, Square};
+
+pub const SEE_VALUES: [i32; 6] = [100, 300, 300, 500, 900, 20000];
+
+#[inline(always)]
+fn value(role: Role) -> i32 {
+    SEE_VALUES[role as usize - 1]
+}
+
+/// Every piece of both sides attacking `sq` through `occupied`
+#[inline]
+fn attackers_to(board: &Board, sq: Square, occupied: Bitboard) -> Bitboard {
+    ((attacks::rook_attacks(sq, occupied) & (board.rooks() | board.queens()))
+        | (attacks::bishop_attacks(sq, occupied) & (board.bishops() | board.queens()))
+        | (attacks::knight_attacks(sq) & board.knights())
+        | (attacks::king_attacks(sq) & board.kings())
+        | (attacks::pawn_attacks(Color::White, sq) & board.pawns() & board.black())
+        | (attacks::pawn_attacks(Color::Black, sq) & board.pawns() & board.white()))
+        & occupied
+}
+
+/// Material won by `m` once every exchange on its target square is played out,
+/// attackers hidden behind sliders join in as the pieces in front of them capture
+/// https://www.chessprogramming.org/SEE_-_The_Swap_Algorithm
+pub fn see(board: &Chess, m: &Move) -> i32 {
+    let b = board.board();
+    let (from, to) = match *m {
+        Move::Normal { from, to, .. } | Move::EnPassant { from, to } => (from, to),
+        _ => return 0,
+    };
+
+    let mut occupied = b.occupied() ^ Bitboard::from_square(from);
+    let mut gain = [0i32; 32];
+    let mut piece_value = value(m.promotion().unwrap_or_else(|| m.role()));
+
+    gain[0] = m.capture().map_or(0, value);
+    if let Some(promotion) = m.promotion() {
+        gain[0] += value(promotion) - value(Role::Pawn);
+    }
+    if let Move::EnPassant { .. } = *m {
+        occupied ^= Bitboard::from_square(Square::new((to as u32 & 7) | (from as u32 & 56)));
+    }
+
+    let mut side = !board.turn();
+    let mut depth = 0;
+    loop {
+        let attackers = attackers_to(b, to, occupied);
+        let ours = attackers & b.by_color(side);
+        let least = [
+            Role::Pawn,
+            Role::Knight,
+            Role::Bishop,
+            Role::Rook,
+            Role::Queen,
+            Role::King,
+        ]
+        .iter()
+        .find_map(|&role| (ours & b.by_role(role)).first().map(|sq| (role, sq)));
+        let (role, sq) = match least {
+            Some(x) => x,
+            None => break,
+        };
+        // the king may only take last
+        if role == Role::King && !(attackers & b.by_color(!side)).is_empty() {
+            break;
+        }
+
+        depth += 1;
+        gain[depth] = piece_value - gain[depth - 1];
+
+        piece_value = value(role);
+        occupied ^= Bitboard::from_square(sq);
+        side = !side;
+    }
+
+    // either side may stop capturing when going on would lose material
+    while depth > 0 {
+        gain[depth - 1] = -(-gain[depth - 1]).max(gain[depth]);
+        depth -= 1;
+    }
+    gain[0]
+}
+
+/// Whether `m` does not lose material beyond `threshold`
+#[inline]
+pub fn see_ge(board: &Chess, m: &Move, threshold: i32) -> bool {
+    see(board, m) >= threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::uci::Uci;
+    use crate::chess::{CastlingMode, FromSetup};
+
+    fn see_of(fen: &str, m: &str) -> i32 {
+        let board: Chess =
+            Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap();
+        let m = Uci::from_str(m).unwrap().to_move(&board).unwrap();
+        see(&board, &m)
+    }
+
+    #[test]
+    fn undefended() {
+        assert_eq!(
+            see_of("1k1r4/1pp4p/p7/4p3/8/P5P1/1PP4P/2K1R3 w - - 0 1", "e1e5"),
+            100
+        );
+        assert_eq!(see_of("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6"), 100);
+    }
+
+    #[test]
+    fn defended() {
+        // QxP, PxQ
+        assert_eq!(see_of("4k3/8/2p5/3p4/8/8/3Q4/4K3 w - - 0 1", "d2d5"), -800);
+        // the king cannot recapture a defended piece
+        assert_eq!(see_of("8/8/8/3k4/3p4/8/3R4/3RK3 w - - 0 1", "d2d4"), 100);
+        // quiet moves to attacked squares lose the piece
+        assert_eq!(see_of("4k3/8/2p5/8/8/8/3Q4/4K3 w - - 0 1", "d2d5"), -900);
+    }
+
+    #[test]
+    fn x_rays() {
+        // the rook on d1 backs up the one on d2
+        assert_eq!(see_of("4k3/3r4/8/3p4/8/8/3R4/3R2K1 w - - 0 1", "d2d5"), 100);
+        // both sides have batteries: the queens behind rook and bishop join in
+        assert_eq!(
+            see_of(
+                "1k1r3q/1ppn3p/p4b2/4p3/8/P2N2P1/1PP1R1BP/2K1Q3 w - - 0 1",
+                "d3e5"
+            ),
+            -200
+        );
+    }
+
+    #[test]
+    fn promotions() {
+        assert_eq!(
+            see_of("3r3k/2P5/8/8/8/8/8/4K3 w - - 0 1", "c7d8q"),
+            500 + 800
+        );
+        // the new queen is lost
+        assert_eq!(see_of("1r5k/2P5/8/8/8/8/8/4K3 w - - 0 1", "c7c8q"), -100);
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::chess::{Chess, Move};
+use crate::options::EngineOptions;
+use crate::search::Search;
+use crate::timeman::{TimeControl, Timer};
+use crate::tt::TranspositionTable;
+
+pub const SEARCH_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Lazy SMP: helper threads search the same position and communicate only
+/// through the shared transposition table, the main thread reports the result
+/// https://www.chessprogramming.org/Lazy_SMP
+#[allow(clippy::too_many_arguments)]
+pub fn lazy_smp(
+    board: &Chess,
+    control: TimeControl,
+    clock: Option<TimeControl>,
+    stop: Arc<AtomicBool>,
+    tt: Arc<TranspositionTable>,
+    move_table: &[u64],
+    search_moves: &[Move],
+    options: &EngineOptions,
+    ponder: Option<Arc<AtomicBool>>,
+) -> (Move, i32, Option<Move>) {
+    let threads = options.threads.max(1);
+    tt.new_search();
+    let node_counts: Arc<Vec<AtomicU64>> =
+        Arc::new((0..threads).map(|_| AtomicU64::new(0)).collect());
+
+    // helpers run until the main thread raises the stop flag, but in a mate search
+    // they have to prune as little as the main thread, they share its TT
+    let helper_control = match control {
+        TimeControl::Mate(_) => control,
+        _ => TimeControl::Infinite,
+    };
+
+    let helpers: Vec<_> = (1..threads)
+        .map(|id| {
+            let board = board.clone();
+            let stop = stop.clone();
+            let tt = tt.clone();
+            let node_counts = node_counts.clone();
+            let move_table = move_table.to_vec();
+            let search_moves = search_moves.to_vec();
+            let options = options.clone();
+            thread::Builder::new()
+                .name(format!("helper {}", id))
+                .stack_size(SEARCH_STACK_SIZE)
+                .spawn(move || {
+                    let timer = Timer::new(&board, helper_control, stop);
+                    let mut searcher = Search::new(timer, tt);
+                    searcher.thread_id = id;
+                    searcher.node_counts = node_counts;
+                    searcher.move_table = move_table;
+                    searcher.search_moves = search_moves;
+                    searcher.set_options(&options);
+                    searcher.mtdf(&board);
+                })
+                .unwrap()
+        })
+        .collect();
+
+    let mut timer = Timer::new(board, control, stop.clone());
+    if let Some(clock) = clock {
+        timer.set_clock(board, clock);
+    }
+    if let Some(ponder) = ponder {
+        timer.set_ponder(ponder);
+    }
+    let mut searcher = Search::new(timer, tt);
+    searcher.node_counts = node_counts;
+    searcher.move_table = move_table.to_vec();
+    searcher.search_moves = search_moves.to_vec();
+    searcher.set_options(options);
+    let (best_move, best_score) = searcher.mtdf(board);
+
+    // a search that ends by itself under go infinite, on a mate or at the depth limit,
+    // still may not answer before stop
+    if control == TimeControl::Infinite {
+        while !stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for helper in helpers {
+        helper.join().unwrap();
+    }
+    stop.store(false, Ordering::Relaxed);
+
+    (best_move, best_score, searcher.ponder_move)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::{CastlingMode, FromSetup};
+    use crate::weight::uci_score;
+    use crate::zobrist;
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    fn options(threads: usize) -> EngineOptions {
+        EngineOptions {
+            threads,
+            use_nnue: false,
+            ..EngineOptions::default()
+        }
+    }
+
+    #[test]
+    fn infinite_waits_for_stop() {
+        let board = from_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let flag = stop.clone();
+        let search = thread::Builder::new()
+            .stack_size(SEARCH_STACK_SIZE)
+            .spawn(move || {
+                let tt = Arc::new(TranspositionTable::with_size(1));
+                let move_table = [zobrist::hash(&board)];
+                let result = lazy_smp(
+                    &board,
+                    TimeControl::Infinite,
+                    None,
+                    flag,
+                    tt,
+                    &move_table,
+                    &[],
+                    &options(1),
+                    None,
+                );
+                tx.send(result).unwrap();
+            })
+            .unwrap();
+
+        // the mate in one is found at once, the answer still waits for stop
+        thread::sleep(Duration::from_millis(500));
+        assert!(rx.try_recv().is_err());
+
+        stop.store(true, Ordering::Relaxed);
+        let (best_move, _, _) = rx.recv().unwrap();
+        search.join().unwrap();
+        assert_eq!(best_move.to_uci(CastlingMode::Standard).to_string(), "a1a8");
+    }
+
+    #[test]
+    fn mate_with_helpers() {
+        let puzzles = [
+            ("6k1/pp4p1/2p5/2bp4/8/P5Pb/1P3rrP/2BRRN1K b - - 0 1", 2),
+            ("k7/8/8/3K4/8/8/8/6Q1 w - - 0 1", 3),
+            ("7k/8/8/4K3/8/8/8/R7 w - - 0 1", 3),
+        ];
+        for (fen, moves) in puzzles.iter() {
+            let board = from_fen(fen);
+            let stop = Arc::new(AtomicBool::new(false));
+            let tt = Arc::new(TranspositionTable::with_size(1));
+            let (_, score, _) = lazy_smp(
+                &board,
+                TimeControl::Mate(*moves),
+                None,
+                stop,
+                tt,
+                &[zobrist::hash(&board)],
+                &[],
+                &options(2),
+                None,
+            );
+            assert_eq!(uci_score(score), format!("mate {}", moves), "{}", fen);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use shakmaty_syzygy::{Tablebase, Wdl};
+
+use crate::chess::{Chess, Move, Position, Setup};
+use crate::search::Ply;
+
+/// Only compiles while `shakmaty-syzygy` resolves to the same shakmaty as the
+/// engine, so the build itself checks the `[patch.crates-io]` override
+pub type Tables = Tablebase<Chess>;
+
+/// Scores of tablebase wins, above any evaluation and below the mate scores
+pub const TB_WIN_SCORE: i32 = 25000;
+
+lazy_static! {
+    static ref TABLEBASE: RwLock<Option<Arc<Tables>>> = RwLock::new(None);
+}
+
+/// Loads the tables of every directory in `path`, separated like PATH,
+/// an empty path unloads them. Returns the number of tables found
+pub fn syzygy_init(path: &str) -> io::Result<usize> {
+    let mut tables = Tablebase::new();
+    let mut count = 0;
+    for dir in path.split(if cfg!(windows) { ';' } else { ':' }) {
+        if !dir.trim().is_empty() {
+            count += tables.add_directory(dir.trim())?;
+        }
+    }
+    *TABLEBASE.write().unwrap() = if count > 0 {
+        Some(Arc::new(tables))
+    } else {
+        None
+    };
+    Ok(count)
+}
+
+/// The currently loaded tables, if any
+#[inline]
+pub fn tablebase() -> Option<Arc<Tables>> {
+    TABLEBASE.read().unwrap().clone()
+}
+
+/// Loss = -2, blessed loss = -1, draw = 0, cursed win = 1, win = 2
+#[inline]
+pub fn wdl_value(wdl: Wdl) -> i32 {
+    match wdl {
+        Wdl::Loss => -2,
+        Wdl::BlessedLoss => -1,
+        Wdl::Draw => 0,
+        Wdl::CursedWin => 1,
+        Wdl::Win => 2,
+    }
+}
+
+/// Search score of a tablebase result `ply` plies from the root,
+/// cursed wins and blessed losses are draws under the fifty-move rule
+#[inline]
+pub fn wdl_score(wdl: Wdl, ply: Ply) -> i32 {
+    match wdl_value(wdl) {
+        2 => TB_WIN_SCORE - ply as i32,
+        -2 => -TB_WIN_SCORE + ply as i32,
+        _ => 0,
+    }
+}
+
+/// Whether the tables can answer for `board`, they know nothing of castling
+#[inline]
+pub fn can_probe(tables: &Tables, board: &Chess) -> bool {
+    board.castling_rights().is_empty() && board.board().occupied().count() <= tables.max_pieces()
+}
+
+/// WDL of `board` for the side to move
+#[inline]
+pub fn probe_wdl(tables: &Tables, board: &Chess) -> Option<Wdl> {
+    if !can_probe(tables, board) {
+        return None;
+    }
+    tables.probe_wdl(board).ok()
+}
+
+/// The root moves that keep the best result, using DTZ to tell wins
+/// that can still be converted within the fifty-move rule from those that cannot
+pub fn root_moves(tables: &Tables, board: &Chess) -> Option<Vec<Move>> {
+    if !can_probe(tables, board) {
+        return None;
+    }
+    rank_root_moves(
+        board,
+        |x| tables.probe_wdl(x).ok(),
+        |x| tables.probe_dtz(x).ok().map(|dtz| dtz.0),
+    )
+}
+
+/// Ranks the root moves with `wdl` and `dtz` probing the positions after them
+fn rank_root_moves<W, D>(board: &Chess, wdl: W, dtz: D) -> Option<Vec<Move>>
+where
+    W: Fn(&Chess) -> Option<Wdl>,
+    D: Fn(&Chess) -> Option<i32>,
+{
+    let mut ranked = Vec::new();
+    for m in board.legal_moves() {
+        let mut nb = board.clone();
+        nb.play_unchecked(&m);
+        let mut value = -wdl_value(wdl(&nb)?);
+        if value == 2 && dtz(&nb)?.unsigned_abs() + nb.halfmoves() > 100 {
+            value = 1;
+        }
+        ranked.push((m, value));
+    }
+
+    let best = ranked.iter().map(|x| x.1).max()?;
+    Some(
+        ranked
+            .into_iter()
+            .filter(|x| x.1 == best)
+            .map(|x| x.0)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::uci::Uci;
+    use crate::chess::{attacks, CastlingMode, Color, FromSetup};
+
+    const FIXTURES: &str = "./tests/syzygy";
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    #[test]
+    fn scores() {
+        assert_eq!(wdl_score(Wdl::Win, 3), TB_WIN_SCORE - 3);
+        assert_eq!(wdl_score(Wdl::Loss, 4), -TB_WIN_SCORE + 4);
+        assert_eq!(wdl_score(Wdl::CursedWin, 1), 0);
+        assert_eq!(wdl_score(Wdl::BlessedLoss, 1), 0);
+        assert!(TB_WIN_SCORE < crate::weight::INF_SCORE - 200);
+    }
+
+    #[test]
+    fn ranking() {
+        let board = from_fen("8/8/8/8/8/3k4/4R3/7K w - - 95 80");
+        let uci = |x: &str| Uci::from_str(x).unwrap().to_move(&board).unwrap();
+        let rook_safe = |x: &Chess| {
+            let b = x.board();
+            match (b.rooks().first(), b.king_of(Color::Black)) {
+                (Some(rook), Some(king)) => !attacks::king_attacks(king).contains(rook),
+                _ => false,
+            }
+        };
+        // Black loses while the rook is safe, mate comes fast only from the eighth rank
+        let wdl = |x: &Chess| Some(if rook_safe(x) { Wdl::Loss } else { Wdl::Draw });
+        let dtz = |x: &Chess| {
+            let on_eighth = x
+                .board()
+                .rooks()
+                .first()
+                .map_or(false, |sq| sq as usize >= 56);
+            Some(if on_eighth { -3 } else { -10 })
+        };
+
+        // with 96 halfmoves after the move only the quick win is still a win
+        let moves = rank_root_moves(&board, wdl, dtz).unwrap();
+        assert_eq!(moves, vec![uci("e2e8")]);
+
+        // with a fresh counter every safe rook move wins
+        let board = from_fen("8/8/8/8/8/3k4/4R3/7K w - - 0 80");
+        let moves = rank_root_moves(&board, wdl, dtz).unwrap();
+        assert!(moves.contains(&uci("e2e8")));
+        assert!(moves.contains(&uci("e2a2")));
+        assert!(!moves.contains(&uci("e2d2")));
+        assert!(!moves.contains(&uci("h1g1")));
+
+        // a failed probe gives no ranking
+        assert_eq!(rank_root_moves(&board, |_| None, dtz), None);
+    }
+
+    #[test]
+    #[ignore = "requires 3-4 piece tables in ./tests/syzygy"]
+    fn probe() {
+        assert!(syzygy_init(FIXTURES).unwrap() > 0);
+        let tables = tablebase().unwrap();
+
+        let board = from_fen("8/8/8/8/8/3k4/8/3KQ3 w - - 0 1");
+        assert_eq!(probe_wdl(&tables, &board), Some(Wdl::Win));
+        let board = from_fen("8/8/8/8/8/3k4/8/3KQ3 b - - 0 1");
+        assert_eq!(probe_wdl(&tables, &board), Some(Wdl::Loss));
+        let board = from_fen("8/8/8/8/8/3k4/8/3KN3 w - - 0 1");
+        assert_eq!(probe_wdl(&tables, &board), Some(Wdl::Draw));
+    }
+
+    #[test]
+    #[ignore = "requires 3-4 piece tables in ./tests/syzygy"]
+    fn winning_root_moves() {
+        syzygy_init(FIXTURES).unwrap();
+        let tables = tablebase().unwrap();
+
+        // the rook hangs to Kxe2 unless it moves away from the king
+        let board = from_fen("8/8/8/8/8/3k4/4R3/7K w - - 0 1");
+        let moves = root_moves(&tables, &board).unwrap();
+        for m in moves.iter() {
+            let mut nb = board.clone();
+            nb.play_unchecked(m);
+            assert_eq!(probe_wdl(&tables, &nb), Some(Wdl::Loss));
+        }
+        let uci = |x: &str| Uci::from_str(x).unwrap().to_move(&board).unwrap();
+        assert!(moves.contains(&uci("e2e8")));
+        assert!(!moves.contains(&uci("h1g1")));
+        assert!(!moves.contains(&uci("e2d2")));
+
+        // castling rights are not covered by the tables
+        assert_eq!(root_moves(&tables, &Chess::default()), None);
+    }
+}
@@ -1,5 +1,9 @@
-use crate::chess::{Chess, Color, Setup};
+use std::str::FromStr;
+
+use crate::chess::uci::Uci;
+use crate::chess::{Chess, Color, Move, Setup};
 use crate::search::Depth;
+use crate::time::calc_time;
 use std::cmp::min;
 use std::sync;
 use std::sync::atomic::AtomicBool;
@@ -15,6 +19,8 @@ pub enum TimeControl {
     FixedMillis(Time),
     FixedDepth(Depth),
     FixedNodes(u64),
+    /// Searches for a mate in at most this many moves
+    Mate(Depth),
     Variable {
         wtime: Time,
         btime: Time,
@@ -29,9 +35,11 @@ pub struct Timer {
     control: TimeControl,
     start_time: Instant,
     stop: Arc<AtomicBool>,
-    times_checked: u64,
+    ponder: Option<Arc<AtomicBool>>,
     time_target: Time,
     time_maximum: Time,
+    /// Hard limit from the clock when another limit ends the search
+    clock_limit: Option<Time>,
 }
 
 impl Timer {
@@ -39,23 +47,32 @@ impl Timer {
         let mut tm = Timer {
             start_time: Instant::now(),
             stop,
+            ponder: None,
             control,
-            times_checked: 0,
             time_target: 0,
             time_maximum: 0,
+            clock_limit: None,
         };
         tm.calc(&board);
         tm
     }
 
     fn calc(&mut self, board: &Chess) {
+        if let Some((target, maximum)) = Self::allocate(board, self.control) {
+            self.time_target = target;
+            self.time_maximum = maximum;
+        }
+    }
+
+    /// Target and maximum time of a move under `clock`, if it is one
+    fn allocate(board: &Chess, clock: TimeControl) -> Option<(Time, Time)> {
         if let TimeControl::Variable {
             wtime,
             btime,
             winc,
             binc,
             moves_to_go,
-        } = self.control
+        } = clock
         {
             let time = if board.turn() == Color::White {
                 wtime
@@ -69,18 +86,58 @@ impl Timer {
             }
             .unwrap_or(0) as f64;
 
-            let target = time.min(time / moves_to_go.unwrap_or(40) as f64 + inc);
-            self.time_target = target as Time;
-            self.time_maximum = (target + (time - target) / 4.0) as Time;
+            let expected_game_length: u64 = 50;
+            let moves_to_go = moves_to_go.unwrap_or_else(|| {
+                expected_game_length
+                    .saturating_sub(board.fullmoves().get() as u64)
+                    .max(20)
+            });
+
+            let target = time.min(calc_time(moves_to_go.max(1) as f64, time, inc));
+            let maximum = (target + (time - target) / 4.0).min(time - 1.0).max(1.0);
+            return Some((target as Time, maximum as Time));
         }
+        None
     }
 
-    pub fn start_check(&self, depth: Depth) -> bool {
+    /// Keeps the clock as a hard limit of a search ended by depth, nodes, time or mate,
+    /// so that `go depth 20 wtime 1000` does not lose on time. `go infinite` ignores it
+    pub fn set_clock(&mut self, board: &Chess, clock: TimeControl) {
+        if self.control != TimeControl::Infinite {
+            self.clock_limit = Self::allocate(board, clock).map(|x| x.1);
+        }
+    }
+
+    /// Searches without limits while `ponder` is raised, until `ponderhit` clears it
+    pub fn set_ponder(&mut self, ponder: Arc<AtomicBool>) {
+        self.ponder = Some(ponder);
+    }
+
+    /// On ponderhit the clock becomes ours: the limits count from this moment
+    /// while the iterations already completed are kept
+    #[inline]
+    fn is_pondering(&mut self) -> bool {
+        if let Some(ponder) = &self.ponder {
+            if ponder.load(sync::atomic::Ordering::Relaxed) {
+                return true;
+            }
+            self.ponder = None;
+            self.start_time = Instant::now();
+        }
+        false
+    }
+
+    pub fn start_check(&mut self, depth: Depth) -> bool {
+        if self.is_pondering() {
+            return true;
+        }
         let start = match self.control {
             TimeControl::Infinite => true,
             TimeControl::FixedMillis(millis) => self.elapsed() <= millis,
             TimeControl::FixedDepth(stop_depth) => depth <= stop_depth,
             TimeControl::FixedNodes(_) => true,
+            // a mate in n needs at most 2n - 1 plies
+            TimeControl::Mate(moves) => depth <= moves.saturating_mul(2) - 1,
             TimeControl::Variable { .. } => {
                 return self.elapsed() <= self.time_target / 2;
             }
@@ -88,21 +145,38 @@ impl Timer {
         start
     }
 
-    pub fn stop_check(&mut self) -> bool {
-        self.times_checked += 1;
-        if self.times_checked & 0x1000 == 0 && self.stop.load(sync::atomic::Ordering::Relaxed) {
+    /// Whether the search has to stop, `nodes` being those searched by all threads so far
+    pub fn stop_check(&mut self, nodes: u64) -> bool {
+        if self.stop.load(sync::atomic::Ordering::Relaxed) {
             return true;
         }
+        if self.is_pondering() {
+            return false;
+        }
+        if let Some(limit) = self.clock_limit {
+            if self.elapsed() >= limit {
+                return true;
+            }
+        }
         let stop = match self.control {
             TimeControl::Infinite => false,
             TimeControl::FixedMillis(millis) => self.elapsed() > millis,
             TimeControl::Variable { .. } => self.elapsed() >= self.time_maximum,
             TimeControl::FixedDepth(_) => false,
-            TimeControl::FixedNodes(nodes) => self.times_checked >= nodes,
+            TimeControl::Mate(_) => false,
+            TimeControl::FixedNodes(limit) => nodes >= limit,
         };
         stop
     }
 
+    /// The number of moves of `go mate`, if that is the limit
+    pub fn mate_limit(&self) -> Option<Depth> {
+        match self.control {
+            TimeControl::Mate(moves) => Some(moves),
+            _ => None,
+        }
+    }
+
     #[inline(always)]
     pub fn elapsed(&self) -> Time {
         self.start_time.elapsed().as_millis() as Time
@@ -120,47 +194,333 @@ impl Timer {
     }
 }
 
-impl From<&str> for TimeControl {
-    fn from(s: &str) -> Self {
-        let mut result = TimeControl::Infinite;
-
-        let mut wtime: Option<Time> = None;
-        let mut btime: Option<Time> = None;
-        let mut winc: Option<Time> = None;
-        let mut binc: Option<Time> = None;
-        let mut moves_to_go: Option<u64> = None;
-
-        let mut split = s.split_whitespace();
-        while let Some(s) = split.next() {
-            if s == "movetime" {
-                result = TimeControl::FixedMillis(split.next().unwrap().parse().unwrap());
-            } else if s == "infinite" {
-                result = TimeControl::Infinite;
-            } else if s == "nodes" {
-                result = TimeControl::FixedNodes(split.next().unwrap().parse().unwrap());
-            } else if s == "depth" {
-                result = TimeControl::FixedDepth(split.next().unwrap().parse().unwrap());
-            } else if s == "wtime" {
-                wtime = split.next().unwrap().parse().ok();
-            } else if s == "btime" {
-                btime = split.next().unwrap().parse().ok();
-            } else if s == "winc" {
-                winc = split.next().unwrap().parse().ok();
-            } else if s == "binc" {
-                binc = split.next().unwrap().parse().ok();
-            } else if s == "movestogo" {
-                moves_to_go = split.next().unwrap().parse().ok();
+impl TimeControl {
+    /// Reserves `overhead` milliseconds of every clock for communication lag
+    pub fn with_overhead(self, overhead: Time) -> Self {
+        match self {
+            TimeControl::FixedMillis(millis) => {
+                TimeControl::FixedMillis(millis.saturating_sub(overhead).max(1))
             }
-        }
-        if wtime != None {
-            result = TimeControl::Variable {
-                wtime: wtime.unwrap(),
-                btime: btime.unwrap(),
+            TimeControl::Variable {
+                wtime,
+                btime,
                 winc,
                 binc,
                 moves_to_go,
-            };
+            } => TimeControl::Variable {
+                wtime: wtime.saturating_sub(overhead).max(1),
+                btime: btime.saturating_sub(overhead).max(1),
+                winc,
+                binc,
+                moves_to_go,
+            },
+            x => x,
+        }
+    }
+}
+
+/// Parses a depth, clamping values that do not fit
+fn parse_depth(s: &str) -> Option<Depth> {
+    s.parse::<i64>()
+        .ok()
+        .map(|x| x.max(Depth::MIN as i64).min(Depth::MAX as i64) as Depth)
+}
+
+/// The explicit limit and the clock of the arguments of `go`, in any order
+fn parse_go(s: &str) -> (Option<TimeControl>, Option<TimeControl>) {
+    let mut result = None;
+
+    let mut wtime: Option<Time> = None;
+    let mut btime: Option<Time> = None;
+    let mut winc: Option<Time> = None;
+    let mut binc: Option<Time> = None;
+    let mut moves_to_go: Option<u64> = None;
+
+    let mut split = s.split_whitespace().peekable();
+    while let Some(s) = split.next() {
+        match s {
+            "infinite" => result = Some(TimeControl::Infinite),
+            "movetime" => {
+                result = split
+                    .next()
+                    .and_then(|x| x.parse().ok())
+                    .map(TimeControl::FixedMillis)
+                    .or(result)
+            }
+            "nodes" => {
+                result = split
+                    .next()
+                    .and_then(|x| x.parse().ok())
+                    .map(TimeControl::FixedNodes)
+                    .or(result)
+            }
+            "depth" => {
+                result = split
+                    .next()
+                    .and_then(parse_depth)
+                    .map(|x| TimeControl::FixedDepth(x.max(1)))
+                    .or(result)
+            }
+            "mate" => {
+                result = split
+                    .next()
+                    .and_then(parse_depth)
+                    .filter(|x| *x > 0)
+                    .map(TimeControl::Mate)
+                    .or(result)
+            }
+            "wtime" => wtime = split.next().and_then(|x| x.parse().ok()),
+            "btime" => btime = split.next().and_then(|x| x.parse().ok()),
+            "winc" => winc = split.next().and_then(|x| x.parse().ok()),
+            "binc" => binc = split.next().and_then(|x| x.parse().ok()),
+            "movestogo" => moves_to_go = split.next().and_then(|x| x.parse().ok()),
+            "searchmoves" => {
+                // the moves run until the next keyword
+                while let Some(x) = split.peek() {
+                    if GO_KEYWORDS.contains(x) {
+                        break;
+                    }
+                    split.next();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let clock = if wtime.is_some() || btime.is_some() {
+        let wtime = wtime.or(btime).unwrap();
+        let btime = btime.unwrap_or(wtime);
+        Some(TimeControl::Variable {
+            wtime,
+            btime,
+            winc,
+            binc,
+            moves_to_go,
+        })
+    } else {
+        None
+    };
+    (result, clock)
+}
+
+/// The clock given to `go`, which stays a hard limit next to an explicit one
+pub fn parse_clock(s: &str) -> Option<TimeControl> {
+    parse_go(s).1
+}
+
+/// Parses the arguments of `go`, in any order.
+/// Explicit limits take precedence over the clock, see `parse_clock`
+impl From<&str> for TimeControl {
+    fn from(s: &str) -> Self {
+        let (result, clock) = parse_go(s);
+        result.or(clock).unwrap_or(TimeControl::Infinite)
+    }
+}
+
+/// Collects the moves following `searchmoves` in the arguments of `go`.
+/// Invalid and illegal moves are left out and reported, the search still has to answer:
+/// when none is left it covers every legal move
+pub fn parse_searchmoves(s: &str, board: &Chess) -> (Vec<Move>, Vec<String>) {
+    let mut result: Vec<Move> = Vec::new();
+    let mut errors = Vec::new();
+    let split = s
+        .split_whitespace()
+        .skip_while(|x| *x != "searchmoves")
+        .skip(1);
+    for x in split {
+        if GO_KEYWORDS.contains(&x) {
+            break;
         }
-        result
+        let m = match Uci::from_str(x) {
+            Ok(uci) => match uci.to_move(board) {
+                Ok(m) => m,
+                Err(_) => {
+                    errors.push(format!("Illegal move in searchmoves: {}", x));
+                    continue;
+                }
+            },
+            Err(_) => {
+                errors.push(format!("Invalid move in searchmoves: {}", x));
+                continue;
+            }
+        };
+        if !result.contains(&m) {
+            result.push(m);
+        }
+    }
+    (result, errors)
+}
+
+const GO_KEYWORDS: [&str; 12] = [
+    "wtime",
+    "btime",
+    "winc",
+    "binc",
+    "movestogo",
+    "depth",
+    "nodes",
+    "movetime",
+    "mate",
+    "infinite",
+    "searchmoves",
+    "ponder",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_any_order() {
+        let expected = TimeControl::Variable {
+            wtime: 60000,
+            btime: 50000,
+            winc: Some(1000),
+            binc: Some(500),
+            moves_to_go: Some(20),
+        };
+        assert_eq!(
+            TimeControl::from("wtime 60000 btime 50000 winc 1000 binc 500 movestogo 20"),
+            expected
+        );
+        assert_eq!(
+            TimeControl::from("movestogo 20 binc 500 btime 50000 winc 1000 wtime 60000"),
+            expected
+        );
+        assert_eq!(
+            TimeControl::from(
+                "searchmoves e2e4 d2d4 wtime 60000 btime 50000 winc 1000 binc 500 movestogo 20"
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!(TimeControl::from(""), TimeControl::Infinite);
+        assert_eq!(TimeControl::from("infinite"), TimeControl::Infinite);
+        assert_eq!(TimeControl::from("depth 7"), TimeControl::FixedDepth(7));
+        assert_eq!(
+            TimeControl::from("nodes 100000"),
+            TimeControl::FixedNodes(100000)
+        );
+        assert_eq!(
+            TimeControl::from("movetime 1500"),
+            TimeControl::FixedMillis(1500)
+        );
+        assert_eq!(TimeControl::from("mate 3"), TimeControl::Mate(3));
+        assert_eq!(TimeControl::from("mate 0"), TimeControl::Infinite);
+        assert_eq!(
+            TimeControl::from("wtime 1000 btime 1000 depth 12"),
+            TimeControl::FixedDepth(12)
+        );
+        assert_eq!(
+            parse_clock("wtime 1000 btime 900 depth 12"),
+            Some(TimeControl::Variable {
+                wtime: 1000,
+                btime: 900,
+                winc: None,
+                binc: None,
+                moves_to_go: None,
+            })
+        );
+        assert_eq!(parse_clock("depth 12"), None);
+        // out of range depths are clamped
+        assert_eq!(
+            TimeControl::from("depth 200"),
+            TimeControl::FixedDepth(Depth::MAX)
+        );
+        assert_eq!(TimeControl::from("depth 0"), TimeControl::FixedDepth(1));
+        assert_eq!(TimeControl::from("mate 500"), TimeControl::Mate(Depth::MAX));
+        assert_eq!(
+            TimeControl::from("ponder wtime 1000 btime 1000"),
+            TimeControl::Variable {
+                wtime: 1000,
+                btime: 1000,
+                winc: None,
+                binc: None,
+                moves_to_go: None,
+            }
+        );
+        // malformed values do not crash
+        assert_eq!(TimeControl::from("depth"), TimeControl::Infinite);
+        assert_eq!(TimeControl::from("movetime abc"), TimeControl::Infinite);
+    }
+
+    #[test]
+    fn searchmoves() {
+        let board = Chess::default();
+        let uci = |moves: Vec<Move>| {
+            moves
+                .iter()
+                .map(|m| Uci::from_standard(m).to_string())
+                .collect::<Vec<_>>()
+        };
+        let (moves, errors) =
+            parse_searchmoves("wtime 100 searchmoves e2e4 d2d4 e2e4 btime 100", &board);
+        assert_eq!(uci(moves), vec!["e2e4", "d2d4"]);
+        assert!(errors.is_empty());
+
+        assert_eq!(parse_searchmoves("depth 5", &board), (vec![], vec![]));
+
+        // bad entries are dropped and reported
+        let (moves, errors) = parse_searchmoves("searchmoves e2e4 e2e5 e2 d2d4", &board);
+        assert_eq!(uci(moves), vec!["e2e4", "d2d4"]);
+        assert_eq!(
+            errors,
+            vec![
+                "Illegal move in searchmoves: e2e5".to_string(),
+                "Invalid move in searchmoves: e2".to_string()
+            ]
+        );
+
+        // with nothing valid left the list is empty, which searches every legal move
+        let (moves, errors) = parse_searchmoves("searchmoves e2e5 depth 3", &board);
+        assert!(moves.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn stop_flag() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut timer = Timer::new(&Chess::default(), TimeControl::Infinite, stop.clone());
+        assert!(!timer.stop_check(0));
+        stop.store(true, sync::atomic::Ordering::Relaxed);
+        // seen on the very next check, whatever the count of checks so far
+        assert!(timer.stop_check(0));
+    }
+
+    #[test]
+    fn clock_is_a_hard_limit() {
+        let board = Chess::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let clock = parse_clock("wtime 1 btime 1").unwrap();
+
+        let mut timer = Timer::new(&board, TimeControl::FixedDepth(20), stop.clone());
+        assert!(!timer.stop_check(0));
+        timer.set_clock(&board, clock);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(timer.stop_check(0));
+
+        let mut timer = Timer::new(&board, TimeControl::Infinite, stop);
+        timer.set_clock(&board, clock);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(!timer.stop_check(0));
+    }
+
+    #[test]
+    fn overhead() {
+        assert_eq!(
+            TimeControl::FixedMillis(100).with_overhead(30),
+            TimeControl::FixedMillis(70)
+        );
+        assert_eq!(
+            TimeControl::FixedMillis(10).with_overhead(30),
+            TimeControl::FixedMillis(1)
+        );
+        assert_eq!(
+            TimeControl::FixedDepth(5).with_overhead(30),
+            TimeControl::FixedDepth(5)
+        );
     }
 }
@@ -1,5 +1,12 @@
-use crate::chess::Move;
-use crate::search::Depth;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::chess::uci::Uci;
+use crate::chess::{CastlingMode, Chess, Move, Role, Square};
+use crate::search::{Depth, Ply};
+use crate::weight::{is_checkmate, INF_SCORE};
+use crate::zobrist;
 
 #[derive(Copy, Clone, Debug, Eq, PartialOrd, PartialEq)]
 pub enum TTFlag {
@@ -15,11 +22,80 @@ impl Default for TTFlag {
     }
 }
 
+impl TTFlag {
+    fn from_u8(x: u8) -> Self {
+        match x {
+            1 => TTFlag::Exact,
+            2 => TTFlag::Upper,
+            4 => TTFlag::Lower,
+            _ => TTFlag::INVALID,
+        }
+    }
+}
+
+/// Packs a move as from | to << 6 | promotion << 12, 0 meaning no move
+pub fn encode_move(m: &Move) -> u16 {
+    match m.to_uci(CastlingMode::Standard) {
+        Uci::Normal {
+            from,
+            to,
+            promotion,
+        } => from as u16 | (to as u16) << 6 | (promotion.map_or(0, |r| r as u16)) << 12,
+        _ => 0,
+    }
+}
+
+/// Unpacks a move, checking that it is legal in `board`
+pub fn decode_move(board: &Chess, code: u16) -> Option<Move> {
+    if code == 0 {
+        return None;
+    }
+    let promotion = match code >> 12 {
+        2 => Some(Role::Knight),
+        3 => Some(Role::Bishop),
+        4 => Some(Role::Rook),
+        5 => Some(Role::Queen),
+        _ => None,
+    };
+    Uci::Normal {
+        from: Square::new((code & 63) as u32),
+        to: Square::new((code >> 6 & 63) as u32),
+        promotion,
+    }
+    .to_move(board)
+    .ok()
+}
+
+/// Mate scores count plies from the root, the TT stores them
+/// as the distance from the node so that they stay valid at any ply
+#[inline]
+pub fn score_to_tt(score: i32, ply: Ply) -> i32 {
+    if !is_checkmate(score) {
+        score
+    } else if score > 0 {
+        score + ply as i32
+    } else {
+        score - ply as i32
+    }
+}
+
+/// Inverse of `score_to_tt` for an entry probed at `ply`
+#[inline]
+pub fn score_from_tt(score: i32, ply: Ply) -> i32 {
+    if !is_checkmate(score) {
+        score
+    } else if score > 0 {
+        score - ply as i32
+    } else {
+        score + ply as i32
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 pub struct TTEntry {
-    pub key: u16,
+    pub key: u64,
     pub score: i32,
-    pub bestmove: Option<Move>,
+    pub bestmove: u16,
     pub depth: Depth,
     pub flag: TTFlag,
 }
@@ -33,9 +109,9 @@ impl TTEntry {
         flags: TTFlag,
     ) -> Self {
         TTEntry {
-            key: (hash >> 48) as u16,
+            key: hash,
             score,
-            bestmove,
+            bestmove: bestmove.as_ref().map_or(0, encode_move),
             depth,
             flag: flags,
         }
@@ -43,14 +119,128 @@ impl TTEntry {
 
     #[inline(always)]
     pub fn is_key_valid(&self, hash: u64) -> bool {
-        self.key == (hash >> 48) as u16
+        self.key == hash
+    }
+
+    /// move << 16 | score << 32 | depth << 48 | flag << 56,
+    /// the key bits and the generation are added by the table
+    #[inline(always)]
+    fn pack(&self) -> u64 {
+        (self.bestmove as u64) << 16
+            | (compress_score(self.score) as u16 as u64) << 32
+            | (self.depth as u8 as u64) << 48
+            | (self.flag as u64) << 56
+    }
+
+    #[inline(always)]
+    fn unpack(hash: u64, data: u64) -> Self {
+        TTEntry {
+            key: hash,
+            bestmove: (data >> 16) as u16,
+            score: expand_score((data >> 32) as u16 as i16),
+            depth: (data >> 48) as u8 as Depth,
+            flag: TTFlag::from_u8((data >> 56) as u8 & 7),
+        }
+    }
+}
+
+/// The index already uses the low bits of the hash, the entry keeps the top 16
+#[inline(always)]
+fn key_bits(hash: u64) -> u64 {
+    hash >> 48
+}
+
+const MATE_16: i32 = i16::MAX as i32;
+const MAX_EVAL_16: i32 = MATE_16 - 1000;
+
+/// Mate scores keep their distance to mate, other scores are clamped
+#[inline(always)]
+fn compress_score(score: i32) -> i16 {
+    if is_checkmate(score) {
+        (score.signum() * (MATE_16 - (INF_SCORE - score.abs()))) as i16
+    } else {
+        score.clamp(-MAX_EVAL_16, MAX_EVAL_16) as i16
+    }
+}
+
+#[inline(always)]
+fn expand_score(score: i16) -> i32 {
+    let score = score as i32;
+    if score.abs() > MAX_EVAL_16 {
+        score.signum() * (INF_SCORE - (MATE_16 - score.abs()))
+    } else {
+        score
+    }
+}
+
+/// A whole entry fits in one atomic word, so reads can never see half of a write
+/// https://www.chessprogramming.org/Shared_Hash_Table#Lockless
+#[derive(Debug, Default)]
+struct AtomicEntry(AtomicU64);
+
+impl AtomicEntry {
+    #[inline(always)]
+    fn load(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    fn store(&self, data: u64) {
+        self.0.store(data, Ordering::Relaxed);
     }
 }
 
-#[derive(Clone, Debug)]
+/// The entries sharing an index, one cache line
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct Bucket {
+    entries: [AtomicEntry; BUCKET_SIZE],
+}
+
+const BUCKET_SIZE: usize = 8;
+
+// the flag takes the low 3 bits of the top byte, the generation the rest
+const AGE_SHIFT: u64 = 59;
+const AGE_MASK: u8 = 31;
+
+#[inline(always)]
+fn age_of(data: u64) -> u8 {
+    (data >> AGE_SHIFT) as u8
+}
+
+const HASH_FILE_MAGIC: [u8; 4] = *b"ICTT";
+// bump whenever the entry layout changes
+const HASH_FILE_VERSION: u32 = 1;
+/// Magic, version, key scheme, bucket count, bucket size and generation
+const HASH_FILE_HEADER: u64 = 4 + 4 + 8 + 8 + 4 + 4;
+
+/// Identifies the zobrist keys, a table is useless with other keys
+fn key_scheme() -> u64 {
+    zobrist::hash(&Chess::default())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Shared between the search threads
+#[derive(Debug)]
 pub struct TranspositionTable {
-    pub table: Vec<TTEntry>,
+    table: Vec<Bucket>,
     pub size: usize,
+    generation: AtomicU8,
 }
 
 impl Default for TranspositionTable {
@@ -62,32 +252,395 @@ impl Default for TranspositionTable {
 impl TranspositionTable {
     pub fn with_size(size_mb: u64) -> Self {
         let hash_size = 0x100000 * size_mb;
-        let struct_size = std::mem::size_of::<TTEntry>() as u64;
-        let hash_entries = hash_size / struct_size;
-        let mut table = Vec::with_capacity(hash_entries as usize);
-        table.resize(hash_entries as usize, TTEntry::default());
+        let struct_size = std::mem::size_of::<Bucket>() as u64;
+        let buckets = hash_size / struct_size;
+        let mut table = Vec::with_capacity(buckets as usize);
+        table.resize_with(buckets as usize, Bucket::default);
         TranspositionTable {
             table,
-            size: hash_entries as usize,
+            size: buckets as usize,
+            generation: AtomicU8::new(0),
         }
     }
 
-    pub fn get(&self, hash: u64) -> Option<&TTEntry> {
-        unsafe {
-            let res = self.table.get_unchecked(hash as usize % self.size);
-            if res.is_key_valid(hash) {
-                Some(res)
-            } else {
-                None
+    #[inline(always)]
+    fn bucket(&self, hash: u64) -> &Bucket {
+        unsafe { self.table.get_unchecked(hash as usize % self.size) }
+    }
+
+    /// Called once per search so that entries of earlier searches get replaced first
+    pub fn new_search(&self) {
+        let generation = (self.generation.load(Ordering::Relaxed) + 1) & AGE_MASK;
+        self.generation.store(generation, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, hash: u64) -> Option<TTEntry> {
+        let key = key_bits(hash);
+        for slot in self.bucket(hash).entries.iter() {
+            let data = slot.load();
+            if data & 0xFFFF == key && data != 0 {
+                return Some(TTEntry::unpack(hash, data));
             }
         }
+        None
     }
 
-    pub fn insert(&mut self, hash: u64, entry: TTEntry) {
-        self.table[hash as usize % self.size] = entry;
+    /// Overwrites the entry of the same position if the new one is not much shallower,
+    /// otherwise the entry that is the shallowest once its age is accounted for
+    pub fn insert(&self, hash: u64, mut entry: TTEntry) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        let bucket = self.bucket(hash);
+        let key = key_bits(hash);
+
+        let mut victim = &bucket.entries[0];
+        let mut victim_value = i32::MAX;
+        for slot in bucket.entries.iter() {
+            let data = slot.load();
+            if data & 0xFFFF == key && data != 0 {
+                let old = TTEntry::unpack(hash, data);
+                if entry.flag != TTFlag::Exact
+                    && age_of(data) == generation
+                    && entry.depth + 3 < old.depth
+                {
+                    return;
+                }
+                if entry.bestmove == 0 {
+                    entry.bestmove = old.bestmove;
+                }
+                victim = slot;
+                break;
+            }
+            if data == 0 {
+                victim = slot;
+                break;
+            }
+            let age = generation.wrapping_sub(age_of(data)) & AGE_MASK;
+            let value = TTEntry::unpack(hash, data).depth as i32 - 8 * age as i32;
+            if value < victim_value {
+                victim = slot;
+                victim_value = value;
+            }
+        }
+
+        victim.store(key | entry.pack() | (generation as u64) << AGE_SHIFT);
+    }
+
+    /// Permille of the sampled entries written during the current search
+    pub fn hashfull(&self) -> usize {
+        let generation = self.generation.load(Ordering::Relaxed);
+        let buckets = self.size.min(1000 / BUCKET_SIZE);
+        let used = self.table[..buckets]
+            .iter()
+            .flat_map(|x| x.entries.iter())
+            .filter(|x| {
+                let data = x.load();
+                data != 0 && age_of(data) == generation
+            })
+            .count();
+        used * 1000 / (buckets * BUCKET_SIZE).max(1)
+    }
+
+    /// Writes the table to `path`:
+    /// magic, version, key scheme, bucket count, bucket size, generation, then every entry
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(&HASH_FILE_MAGIC)?;
+        w.write_all(&HASH_FILE_VERSION.to_le_bytes())?;
+        w.write_all(&key_scheme().to_le_bytes())?;
+        w.write_all(&(self.size as u64).to_le_bytes())?;
+        w.write_all(&(BUCKET_SIZE as u32).to_le_bytes())?;
+        w.write_all(&(self.generation.load(Ordering::Relaxed) as u32).to_le_bytes())?;
+        for slot in self.table.iter().flat_map(|x| x.entries.iter()) {
+            w.write_all(&slot.load().to_le_bytes())?;
+        }
+        w.flush()
+    }
+
+    /// Reads a table written by `save`, which must have the same size
+    pub fn load(&self, path: &str) -> io::Result<()> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if magic != HASH_FILE_MAGIC {
+            return Err(invalid_data("not a hash file".to_string()));
+        }
+        let version = read_u32(&mut r)?;
+        if version != HASH_FILE_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported hash file version {}",
+                version
+            )));
+        }
+        if read_u64(&mut r)? != key_scheme() {
+            return Err(invalid_data(
+                "hash file was written with other zobrist keys".to_string(),
+            ));
+        }
+        let size = read_u64(&mut r)?;
+        let bucket_size = read_u32(&mut r)?;
+        if size != self.size as u64 || bucket_size != BUCKET_SIZE as u32 {
+            return Err(invalid_data(format!(
+                "hash file holds {} entries, the table {}, set Hash to the size it was saved with",
+                size * bucket_size as u64,
+                self.size * BUCKET_SIZE
+            )));
+        }
+        let generation = read_u32(&mut r)? as u8 & AGE_MASK;
+
+        // the length is checked before the table is touched,
+        // a truncated or oversized file leaves it as it was
+        let entries = self.size * BUCKET_SIZE;
+        let expected = HASH_FILE_HEADER + entries as u64 * 8;
+        let len = r.get_ref().metadata()?.len();
+        if len != expected {
+            return Err(invalid_data(format!(
+                "hash file has {} bytes instead of {}",
+                len, expected
+            )));
+        }
+
+        // streamed in chunks, the table can take most of the memory
+        let mut chunk = [0u8; 8 * 1024];
+        let mut slots = self.table.iter().flat_map(|x| x.entries.iter());
+        let mut left = entries;
+        while left > 0 {
+            let n = left.min(chunk.len() / 8);
+            r.read_exact(&mut chunk[..n * 8])?;
+            for (bytes, slot) in chunk[..n * 8].chunks_exact(8).zip(slots.by_ref()) {
+                let mut word = [0u8; 8];
+                word.copy_from_slice(bytes);
+                slot.store(u64::from_le_bytes(word));
+            }
+            left -= n;
+        }
+        self.generation.store(generation, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn clear(&mut self) {
-        self.table = vec![TTEntry::default(); self.size];
+    pub fn clear(&self) {
+        for slot in self.table.iter().flat_map(|x| x.entries.iter()) {
+            slot.store(0);
+        }
+        self.generation.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::{FromSetup, Position};
+
+    #[test]
+    fn move_codes() {
+        let board: Chess = Chess::from_setup(
+            &Fen::from_str("r3k2r/pPpp1ppp/8/3Pp3/8/8/PPP2PPP/R3K2R w KQkq e6 0 1").unwrap(),
+            CastlingMode::Standard,
+        )
+        .unwrap();
+        for m in board.legal_moves() {
+            assert_eq!(decode_move(&board, encode_move(&m)), Some(m));
+        }
+        assert_eq!(decode_move(&board, 0), None);
+    }
+
+    #[test]
+    fn mate_scores() {
+        // mated 2 plies below a node at ply 3
+        let score = -(INF_SCORE - 5);
+        assert_eq!(score_to_tt(score, 3), -(INF_SCORE - 2));
+        assert_eq!(score_from_tt(score_to_tt(score, 3), 3), score);
+        // the same node reached at ply 7
+        assert_eq!(score_from_tt(score_to_tt(score, 3), 7), -(INF_SCORE - 9));
+
+        let score = INF_SCORE - 4;
+        assert_eq!(score_from_tt(score_to_tt(score, 2), 0), INF_SCORE - 2);
+        assert_eq!(score_to_tt(150, 9), 150);
+        assert_eq!(score_from_tt(-150, 9), -150);
+    }
+
+    #[test]
+    fn store_and_probe() {
+        let tt = TranspositionTable::with_size(1);
+        let board = Chess::default();
+        let m = board.legal_moves()[3].clone();
+        let entry = TTEntry::construct(0xdead_beef, -1234, Some(m.clone()), 7, TTFlag::Lower);
+        tt.insert(0xdead_beef, entry.clone());
+        assert_eq!(tt.get(0xdead_beef), Some(entry));
+        assert_eq!(
+            decode_move(&board, tt.get(0xdead_beef).unwrap().bestmove),
+            Some(m)
+        );
+        // same bucket, other key bits
+        assert_eq!(tt.get(0xdead_beef | 1 << 60), None);
+    }
+
+    #[test]
+    fn compact_scores() {
+        let tt = TranspositionTable::with_size(1);
+        for score in [
+            0,
+            -1,
+            2500,
+            -31000,
+            INF_SCORE - 1,
+            -(INF_SCORE - 2),
+            INF_SCORE - 150,
+        ]
+        .iter()
+        {
+            tt.insert(42, TTEntry::construct(42, *score, None, 3, TTFlag::Exact));
+            assert_eq!(tt.get(42).unwrap().score, *score);
+        }
+        // evaluations beyond the 16 bits are clamped, not turned into mates
+        tt.insert(42, TTEntry::construct(42, 100_000, None, 3, TTFlag::Exact));
+        assert_eq!(tt.get(42).unwrap().score, MAX_EVAL_16);
+    }
+
+    /// The entry the table held before entries were packed into one word
+    #[allow(dead_code)]
+    struct UnpackedEntry {
+        key: u16,
+        score: i32,
+        bestmove: Option<Move>,
+        depth: Depth,
+        flag: TTFlag,
+    }
+
+    /// Entries per MB of `Hash` with the packed entries and with the unpacked ones
+    #[test]
+    fn entries_per_mb() {
+        assert_eq!(std::mem::size_of::<Bucket>(), 64);
+
+        let before = 0x100000 / std::mem::size_of::<UnpackedEntry>();
+        let after = TranspositionTable::with_size(1).size * BUCKET_SIZE;
+        assert_eq!(after, 0x100000 / std::mem::size_of::<AtomicEntry>());
+        assert!(after >= 2 * before, "{} entries against {}", after, before);
+    }
+
+    fn entry(key: u64, depth: Depth, flag: TTFlag) -> TTEntry {
+        TTEntry::construct(key, depth as i32, None, depth, flag)
+    }
+
+    /// Keys sharing the bucket of `base` but not the key bits
+    fn bucket_keys(base: u64, count: usize) -> Vec<u64> {
+        (0..count as u64).map(|i| base | i << 48).collect()
+    }
+
+    #[test]
+    fn depth_preferred_replacement() {
+        let tt = TranspositionTable::with_size(1);
+        let keys = bucket_keys(7, BUCKET_SIZE + 1);
+        for (i, key) in keys[..BUCKET_SIZE].iter().enumerate() {
+            let depth = if i == 1 { 2 } else { 8 + i as Depth };
+            tt.insert(*key, entry(*key, depth, TTFlag::Lower));
+        }
+        tt.insert(
+            keys[BUCKET_SIZE],
+            entry(keys[BUCKET_SIZE], 5, TTFlag::Lower),
+        );
+        assert_eq!(tt.get(keys[1]), None);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(tt.get(*key).is_some(), i != 1);
+        }
+
+        // a much shallower bound does not replace the same position
+        tt.insert(keys[0], entry(keys[0], 1, TTFlag::Upper));
+        assert_eq!(tt.get(keys[0]).unwrap().depth, 8);
+        tt.insert(keys[0], entry(keys[0], 1, TTFlag::Exact));
+        assert_eq!(tt.get(keys[0]).unwrap().depth, 1);
+    }
+
+    #[test]
+    fn aging() {
+        let tt = TranspositionTable::with_size(1);
+        let keys = bucket_keys(3, BUCKET_SIZE + 1);
+        for key in keys[..BUCKET_SIZE].iter() {
+            tt.insert(*key, entry(*key, 12, TTFlag::Exact));
+        }
+        tt.new_search();
+        tt.insert(keys[0], entry(keys[0], 12, TTFlag::Exact));
+        // entries of the previous search go first even when they are deeper
+        tt.insert(
+            keys[BUCKET_SIZE],
+            entry(keys[BUCKET_SIZE], 3, TTFlag::Exact),
+        );
+        assert!(tt.get(keys[0]).is_some());
+        assert!(tt.get(keys[BUCKET_SIZE]).is_some());
+        assert_eq!(
+            keys[1..BUCKET_SIZE]
+                .iter()
+                .filter(|x| tt.get(**x).is_some())
+                .count(),
+            BUCKET_SIZE - 2
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("iceburn-tt-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let tt = TranspositionTable::with_size(1);
+        tt.new_search();
+        let keys = bucket_keys(0xabcdef, BUCKET_SIZE);
+        for key in keys.iter() {
+            tt.insert(*key, entry(*key, 9, TTFlag::Lower));
+        }
+        tt.save(path).unwrap();
+
+        let loaded = TranspositionTable::with_size(1);
+        loaded.load(path).unwrap();
+        for key in keys.iter() {
+            assert_eq!(loaded.get(*key), tt.get(*key));
+        }
+        assert_eq!(loaded.hashfull(), tt.hashfull());
+
+        let err = TranspositionTable::with_size(2).load(path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a truncated file is rejected without touching the table
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(
+            bytes.len() as u64,
+            HASH_FILE_HEADER + (loaded.size * BUCKET_SIZE) as u64 * 8
+        );
+        std::fs::write(path, &bytes[..bytes.len() - 8]).unwrap();
+        let untouched = TranspositionTable::with_size(1);
+        untouched.insert(keys[0], entry(keys[0], 3, TTFlag::Upper));
+        assert!(untouched.load(path).is_err());
+        assert_eq!(
+            untouched.get(keys[0]),
+            Some(entry(keys[0], 3, TTFlag::Upper))
+        );
+        assert_eq!(untouched.get(keys[1]), None);
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        std::fs::write(path, &longer).unwrap();
+        assert!(untouched.load(path).is_err());
+
+        std::fs::write(path, b"nothing to see").unwrap();
+        assert!(loaded.load(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hashfull() {
+        let tt = TranspositionTable::with_size(1);
+        assert_eq!(tt.hashfull(), 0);
+        for bucket in 0..(1000 / BUCKET_SIZE) as u64 {
+            for key in bucket_keys(bucket, BUCKET_SIZE) {
+                tt.insert(key, entry(key, 4, TTFlag::Exact));
+            }
+        }
+        assert_eq!(tt.hashfull(), 1000);
+        tt.new_search();
+        assert_eq!(tt.hashfull(), 0);
+        tt.clear();
+        assert_eq!(tt.get(0), None);
     }
 }
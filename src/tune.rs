@@ -0,0 +1,280 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::str::FromStr;
+
+use crate::chess::fen::Fen;
+use crate::chess::{CastlingMode, Chess, FromSetup};
+use crate::weight::{game_phase, trace, MAX_PHASE, PARAM_COUNT};
+
+/// A labelled position reduced to the coefficients of its evaluation
+/// https://www.chessprogramming.org/Texel%27s_Tuning_Method
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub coefs: Vec<(u16, i16)>,
+    /// weight of the opening values, 1 at the start and 0 in a bare endgame
+    pub phase: f64,
+    /// game result for White: 1 win, 0.5 draw, 0 loss
+    pub result: f64,
+}
+
+impl Sample {
+    pub fn new(board: &Chess, result: f64) -> Self {
+        Sample {
+            coefs: trace(board)
+                .into_iter()
+                .map(|(i, c)| (i as u16, c as i16))
+                .collect(),
+            phase: game_phase(board) as f64 / MAX_PHASE as f64,
+            result,
+        }
+    }
+
+    /// Tapered evaluation of the sample for White
+    #[inline]
+    pub fn evaluate(&self, params: &[(f64, f64)]) -> f64 {
+        let (opening, endgame) = self.coefs.iter().fold((0., 0.), |acc, &(i, c)| {
+            let p = params[i as usize];
+            (acc.0 + c as f64 * p.0, acc.1 + c as f64 * p.1)
+        });
+        opening * self.phase + endgame * (1. - self.phase)
+    }
+}
+
+/// Result of a dataset line, written as `1-0`, `0-1`, `1/2-1/2`
+/// with or without quotes, or as a number in brackets or with a decimal point like `0.5`.
+/// The last one wins so that move counters are not taken for results
+fn parse_result(s: &str) -> Option<f64> {
+    for token in s
+        .split(|c: char| c.is_whitespace() || c == ';' || c == ',')
+        .rev()
+    {
+        let trimmed = token.trim_matches(|c| c == '"' || c == '[' || c == ']');
+        match trimmed {
+            "1-0" => return Some(1.),
+            "0-1" => return Some(0.),
+            "1/2-1/2" => return Some(0.5),
+            _ => {}
+        }
+        if trimmed != token || trimmed.contains('.') {
+            if let Ok(v) = trimmed.parse::<f64>() {
+                if (0. ..=1.).contains(&v) {
+                    return Some(v);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Parses `<fen> <result>` or an EPD line like `<epd> c9 "1-0";`,
+/// only the first four fields of the position are used
+pub fn parse_line(line: &str) -> Option<Sample> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 5 {
+        return None;
+    }
+    let fen = format!("{} 0 1", fields[..4].join(" "));
+    let board: Chess =
+        Chess::from_setup(&Fen::from_str(&fen).ok()?, CastlingMode::Standard).ok()?;
+    let result = parse_result(&fields[4..].join(" "))?;
+    Some(Sample::new(&board, result))
+}
+
+/// Loads every parsable line of a dataset, returning them with the count of skipped lines
+pub fn load_dataset(path: &str) -> io::Result<(Vec<Sample>, usize)> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut samples = Vec::new();
+    let mut skipped = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_line(&line) {
+            Some(sample) => samples.push(sample),
+            None => skipped += 1,
+        }
+    }
+    Ok((samples, skipped))
+}
+
+/// Expected result for a score in centipawns
+#[inline]
+pub fn sigmoid(score: f64, k: f64) -> f64 {
+    1. / (1. + 10f64.powf(-k * score / 400.))
+}
+
+/// Mean squared error between the results and the expected results of the evaluations
+pub fn error(params: &[(f64, f64)], samples: &[Sample], k: f64) -> f64 {
+    samples
+        .iter()
+        .map(|x| (x.result - sigmoid(x.evaluate(params), k)).powi(2))
+        .sum::<f64>()
+        / samples.len().max(1) as f64
+}
+
+/// The scaling constant that best fits the current evaluation to the results
+pub fn find_k(params: &[(f64, f64)], samples: &[Sample]) -> f64 {
+    let (mut lo, mut hi) = (0., 10.);
+    for _ in 0..100 {
+        let a = lo + (hi - lo) / 3.;
+        let b = hi - (hi - lo) / 3.;
+        if error(params, samples, a) < error(params, samples, b) {
+            hi = b;
+        } else {
+            lo = a;
+        }
+    }
+    (lo + hi) / 2.
+}
+
+/// Gradient of the error, the evaluation being linear in the parameters
+pub fn gradient(params: &[(f64, f64)], samples: &[Sample], k: f64) -> Vec<(f64, f64)> {
+    let mut grad = vec![(0., 0.); params.len()];
+    let scale = k * 10f64.ln() / 400.;
+    for x in samples.iter() {
+        let s = sigmoid(x.evaluate(params), k);
+        let d = -2. * (x.result - s) * s * (1. - s) * scale;
+        for &(i, c) in x.coefs.iter() {
+            let g = &mut grad[i as usize];
+            g.0 += d * c as f64 * x.phase;
+            g.1 += d * c as f64 * (1. - x.phase);
+        }
+    }
+    let n = samples.len().max(1) as f64;
+    grad.iter_mut().for_each(|g| {
+        g.0 /= n;
+        g.1 /= n;
+    });
+    grad
+}
+
+/// Gradient descent with Adam step sizes over the whole dataset
+pub struct Tuner {
+    pub params: Vec<(f64, f64)>,
+    pub k: f64,
+    pub learning_rate: f64,
+    moments: Vec<(f64, f64)>,
+    velocities: Vec<(f64, f64)>,
+    steps: i32,
+}
+
+impl Tuner {
+    const BETA1: f64 = 0.9;
+    const BETA2: f64 = 0.999;
+    const EPSILON: f64 = 1e-8;
+
+    /// Starts from `params`, fitting the scaling constant to them first
+    pub fn new(params: &[(i32, i32)], samples: &[Sample], learning_rate: f64) -> Self {
+        assert_eq!(params.len(), PARAM_COUNT);
+        let params: Vec<(f64, f64)> = params.iter().map(|x| (x.0 as f64, x.1 as f64)).collect();
+        let k = find_k(&params, samples);
+        Tuner {
+            moments: vec![(0., 0.); params.len()],
+            velocities: vec![(0., 0.); params.len()],
+            params,
+            k,
+            learning_rate,
+            steps: 0,
+        }
+    }
+
+    /// One step over all samples, returning the error before it
+    pub fn step(&mut self, samples: &[Sample]) -> f64 {
+        let before = error(&self.params, samples, self.k);
+        let grad = gradient(&self.params, samples, self.k);
+        self.steps += 1;
+
+        let update = |m: &mut f64, v: &mut f64, g: f64, steps: i32| {
+            *m = Self::BETA1 * *m + (1. - Self::BETA1) * g;
+            *v = Self::BETA2 * *v + (1. - Self::BETA2) * g * g;
+            let m_hat = *m / (1. - Self::BETA1.powi(steps));
+            let v_hat = *v / (1. - Self::BETA2.powi(steps));
+            m_hat / (v_hat.sqrt() + Self::EPSILON)
+        };
+        for i in 0..self.params.len() {
+            let (m, v) = (&mut self.moments[i], &mut self.velocities[i]);
+            self.params[i].0 -=
+                self.learning_rate * update(&mut m.0, &mut v.0, grad[i].0, self.steps);
+            self.params[i].1 -=
+                self.learning_rate * update(&mut m.1, &mut v.1, grad[i].1, self.steps);
+        }
+        before
+    }
+
+    /// The parameters rounded for the engine
+    pub fn rounded(&self) -> Vec<(i32, i32)> {
+        self.params
+            .iter()
+            .map(|x| (x.0.round() as i32, x.1.round() as i32))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weight::{params, MATERIAL_PARAMS};
+
+    #[test]
+    fn parse() {
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3";
+        assert_eq!(
+            parse_line(&format!("{} 0 1 [1.0]", fen)).unwrap().result,
+            1.
+        );
+        assert_eq!(
+            parse_line(&format!("{} 0 1; 0.5", fen)).unwrap().result,
+            0.5
+        );
+        assert_eq!(
+            parse_line(&format!("{} c9 \"0-1\";", fen)).unwrap().result,
+            0.
+        );
+        assert_eq!(
+            parse_line(&format!("{} 12 30 1/2-1/2", fen))
+                .unwrap()
+                .result,
+            0.5
+        );
+        assert_eq!(parse_line(&format!("{} 0 1", fen)), None);
+        assert_eq!(parse_line("not a position 1-0"), None);
+    }
+
+    #[test]
+    fn tapered() {
+        let sample = parse_line("4k3/8/8/8/8/8/8/3QK3 w - - 0 1 [1.0]").unwrap();
+        // a queen is 4 of the 24 phase points
+        assert!((sample.phase - 4. / 24.).abs() < 1e-9);
+
+        let mut params = vec![(0., 0.); PARAM_COUNT];
+        params[MATERIAL_PARAMS + 4] = (1000., 2000.);
+        let expected = 1000. * 4. / 24. + 2000. * 20. / 24.;
+        assert!((sample.evaluate(&params) - expected).abs() < 1e-6);
+
+        let flipped = parse_line("3qk3/8/8/8/8/8/8/4K3 b - - 0 1 [0.0]").unwrap();
+        assert!((flipped.evaluate(&params) + expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tuning_lowers_the_error() {
+        let lines = [
+            "4k3/8/8/8/8/8/8/3NK3 w - - 0 1 [0.5]",
+            "3nk3/8/8/8/8/8/8/4K3 w - - 0 1 [0.5]",
+            "4k3/8/8/8/8/8/8/3RK3 w - - 0 1 [1.0]",
+            "3rk3/8/8/8/8/8/8/4K3 w - - 0 1 [0.0]",
+            "4k3/pppp4/8/8/8/8/PPPPP3/4K3 w - - 0 1 [1.0]",
+            "4k3/ppppp3/8/8/8/8/PPPP4/4K3 w - - 0 1 [0.0]",
+        ];
+        let samples: Vec<Sample> = lines.iter().map(|x| parse_line(x).unwrap()).collect();
+
+        let mut tuner = Tuner::new(&params(), &samples, 1.);
+        assert!(tuner.k > 0.);
+        let first = tuner.step(&samples);
+        for _ in 0..200 {
+            tuner.step(&samples);
+        }
+        assert!(error(&tuner.params, &samples, tuner.k) < first);
+        assert_eq!(tuner.rounded().len(), PARAM_COUNT);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::chess::{Chess, Color, Setup};
+use std::fmt;
+
+use lazy_static::lazy_static;
+
+use crate::chess::{attacks, Bitboard, Chess, Color, Role, Setup};
 
 pub const MATERIAL_OPENING: [i32; 6] = [110, 450, 470, 646, 1170, 20000];
 pub const MATERIAL_ENDGAME: [i32; 6] = [140, 582, 617, 928, 1808, 20000];
@@ -8,6 +12,24 @@ pub fn is_checkmate(value: i32) -> bool {
     value.abs() >= INF_SCORE - 200
 }
 
+/// Moves until mate for a mate score, negative when the side to move gets mated
+pub fn mate_distance(value: i32) -> i32 {
+    if value > 0 {
+        (INF_SCORE - value + 1) / 2
+    } else {
+        -(INF_SCORE + value) / 2
+    }
+}
+
+/// Formats a score for `info score`
+pub fn uci_score(value: i32) -> String {
+    if is_checkmate(value) {
+        format!("mate {}", mate_distance(value))
+    } else {
+        format!("cp {}", value)
+    }
+}
+
 /// https://www.chessprogramming.org/PeSTO%27s_Evaluation_Function
 pub const PSQ: [[i32; 64]; 12] = [
     [
@@ -77,38 +99,716 @@ pub const PSQ: [[i32; 64]; 12] = [
 ];
 
 #[inline]
-pub fn fast_eval(board: &Chess) -> i32 {
+pub fn count_material(board: &Chess) -> i32 {
     let mut score = 0;
-    for (sq, p) in board.board().pieces() {
+    for (_, p) in board.board().pieces() {
         score += MATERIAL_OPENING[p.role as usize - 1] * (p.color as i32 * 2 - 1);
-        score += PSQ[p.role as usize - 1][if p.color == Color::White {
-            sq as usize
-        } else {
-            sq as usize ^ 56
-        }] * (p.color as i32 * 2 - 1)
     }
-    score * ((board.turn() as i32 * 2 - 1) as f64 * 1.7) as i32
+    score * (board.turn() as i32 * 2 - 1) as i32
+}
+
+/// Game phase weights of knights, bishops, rooks and queens
+pub const PHASE_WEIGHTS: [i32; 6] = [0, 1, 1, 2, 4, 0];
+/// Phase of the starting position, 0 is a bare endgame
+pub const MAX_PHASE: i32 = 24;
+
+/// Remaining non-pawn material, from 0 in the endgame up to `MAX_PHASE` in the opening
+pub fn game_phase(board: &Chess) -> i32 {
+    let phase: i32 = board
+        .board()
+        .pieces()
+        .map(|(_, p)| PHASE_WEIGHTS[p.role as usize - 1])
+        .sum();
+    phase.min(MAX_PHASE)
 }
 
+/// Blends an opening and an endgame score by game phase
 #[inline]
-pub fn count_material(board: &Chess) -> i32 {
-    let mut score = 0;
-    for (_, p) in board.board().pieces() {
-        score += MATERIAL_OPENING[p.role as usize - 1] * (p.color as i32 * 2 - 1);
+pub fn taper(opening: i32, endgame: i32, phase: i32) -> i32 {
+    (opening * phase + endgame * (MAX_PHASE - phase)) / MAX_PHASE
+}
+
+/// An (opening, endgame) pair
+pub type S = (i32, i32);
+
+/// Bonus of a passed pawn by its rank, counted from its own side
+pub const PASSED_PAWN: [S; 8] = [
+    (0, 0),
+    (5, 10),
+    (5, 15),
+    (10, 25),
+    (20, 45),
+    (35, 75),
+    (60, 120),
+    (0, 0),
+];
+/// Penalty of every pawn beyond the first on a file
+pub const DOUBLED_PAWN: S = (-10, -20);
+/// Penalty of a pawn without friendly pawns on the adjacent files
+pub const ISOLATED_PAWN: S = (-10, -10);
+/// Bonus per square attacked by a knight, bishop, rook or queen
+/// that is neither occupied by its side nor attacked by enemy pawns
+pub const MOBILITY: [S; 4] = [(4, 4), (5, 5), (2, 4), (1, 2)];
+/// Bonus of a rook on a file without pawns
+pub const ROOK_OPEN_FILE: S = (25, 10);
+/// Bonus of a rook on a file with enemy pawns only
+pub const ROOK_SEMI_OPEN_FILE: S = (10, 5);
+pub const BISHOP_PAIR: S = (30, 50);
+/// Bonus per pawn on the two ranks in front of the king, on its file and the adjacent ones
+pub const KING_SHELTER: S = (10, 0);
+
+/// Layout of the tuner's parameter vector, every entry is an (opening, endgame) pair
+pub const MATERIAL_PARAMS: usize = 0;
+pub const PSQ_PARAMS: usize = MATERIAL_PARAMS + 6;
+pub const PASSED_PAWN_PARAMS: usize = PSQ_PARAMS + 6 * 64;
+pub const DOUBLED_PAWN_PARAM: usize = PASSED_PAWN_PARAMS + 8;
+pub const ISOLATED_PAWN_PARAM: usize = DOUBLED_PAWN_PARAM + 1;
+pub const MOBILITY_PARAMS: usize = ISOLATED_PAWN_PARAM + 1;
+pub const ROOK_OPEN_FILE_PARAM: usize = MOBILITY_PARAMS + 4;
+pub const ROOK_SEMI_OPEN_FILE_PARAM: usize = ROOK_OPEN_FILE_PARAM + 1;
+pub const BISHOP_PAIR_PARAM: usize = ROOK_SEMI_OPEN_FILE_PARAM + 1;
+pub const KING_SHELTER_PARAM: usize = BISHOP_PAIR_PARAM + 1;
+pub const PARAM_COUNT: usize = KING_SHELTER_PARAM + 1;
+
+/// Current values of every tunable parameter
+pub fn params() -> Vec<S> {
+    let mut params = Vec::with_capacity(PARAM_COUNT);
+    for role in 0..6 {
+        params.push((MATERIAL_OPENING[role], MATERIAL_ENDGAME[role]));
+    }
+    for role in 0..6 {
+        for sq in 0..64 {
+            params.push((PSQ[role][sq], PSQ[role + 6][sq]));
+        }
+    }
+    params.extend_from_slice(&PASSED_PAWN);
+    params.push(DOUBLED_PAWN);
+    params.push(ISOLATED_PAWN);
+    params.extend_from_slice(&MOBILITY);
+    params.push(ROOK_OPEN_FILE);
+    params.push(ROOK_SEMI_OPEN_FILE);
+    params.push(BISHOP_PAIR);
+    params.push(KING_SHELTER);
+    params
+}
+
+lazy_static! {
+    static ref PARAMS: Vec<S> = params();
+}
+
+/// The terms of the hand-crafted evaluation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Term {
+    Material,
+    Psq,
+    PassedPawns,
+    DoubledPawns,
+    IsolatedPawns,
+    Mobility,
+    RookFiles,
+    BishopPair,
+    KingShelter,
+}
+
+pub const TERMS: [Term; 9] = [
+    Term::Material,
+    Term::Psq,
+    Term::PassedPawns,
+    Term::DoubledPawns,
+    Term::IsolatedPawns,
+    Term::Mobility,
+    Term::RookFiles,
+    Term::BishopPair,
+    Term::KingShelter,
+];
+
+/// The terms that only depend on the pawns, kept in the pawn cache
+const PAWN_TERMS: [Term; 3] = [Term::PassedPawns, Term::DoubledPawns, Term::IsolatedPawns];
+
+impl Term {
+    pub fn name(self) -> &'static str {
+        match self {
+            Term::Material => "Material",
+            Term::Psq => "Piece squares",
+            Term::PassedPawns => "Passed pawns",
+            Term::DoubledPawns => "Doubled pawns",
+            Term::IsolatedPawns => "Isolated pawns",
+            Term::Mobility => "Mobility",
+            Term::RookFiles => "Rook files",
+            Term::BishopPair => "Bishop pair",
+            Term::KingShelter => "King shelter",
+        }
+    }
+}
+
+/// The evaluation of a position term by term,
+/// with the coefficient of every parameter when traced for the tuner
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Evaluation {
+    /// score of every term for Black and White
+    pub terms: [[S; 2]; 9],
+    pub phase: i32,
+    /// coefficient of every parameter for White minus Black
+    pub coefs: Option<Vec<i32>>,
+}
+
+impl Evaluation {
+    fn new(phase: i32, tracing: bool) -> Self {
+        Evaluation {
+            terms: [[(0, 0); 2]; 9],
+            phase,
+            coefs: if tracing {
+                Some(vec![0; PARAM_COUNT])
+            } else {
+                None
+            },
+        }
+    }
+
+    #[inline]
+    fn add(&mut self, term: Term, color: Color, param: usize, count: i32) {
+        let value = PARAMS[param];
+        let score = &mut self.terms[term as usize][color as usize];
+        score.0 += value.0 * count;
+        score.1 += value.1 * count;
+        if let Some(coefs) = &mut self.coefs {
+            coefs[param] += count * (color as i32 * 2 - 1);
+        }
+    }
+
+    /// Score of a term for White
+    pub fn term(&self, term: Term) -> S {
+        let [black, white] = self.terms[term as usize];
+        (white.0 - black.0, white.1 - black.1)
+    }
+
+    /// Tapered total for White
+    pub fn total(&self) -> i32 {
+        let (opening, endgame) = TERMS.iter().fold((0, 0), |acc, &t| {
+            let score = self.term(t);
+            (acc.0 + score.0, acc.1 + score.1)
+        });
+        taper(opening, endgame, self.phase)
+    }
+}
+
+/// Lists every term for both sides, scores are for White
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>16} | {:>6} {:>6} | {:>6} {:>6} | {:>6} {:>6} {:>6}",
+            "Term", "W mg", "W eg", "B mg", "B eg", "mg", "eg", "total"
+        )?;
+        for &t in TERMS.iter() {
+            let [black, white] = self.terms[t as usize];
+            let score = self.term(t);
+            writeln!(
+                f,
+                "{:>16} | {:>6} {:>6} | {:>6} {:>6} | {:>6} {:>6} {:>6}",
+                t.name(),
+                white.0,
+                white.1,
+                black.0,
+                black.1,
+                score.0,
+                score.1,
+                taper(score.0, score.1, self.phase)
+            )?;
+        }
+        write!(
+            f,
+            "Phase {}/{}, total {} for White",
+            self.phase,
+            MAX_PHASE,
+            self.total()
+        )
     }
-    score * (board.turn() as i32 * 2 - 1) as i32
 }
 
+/// Pawn structure scores by pawn key, pawns rarely change during a search
+/// https://www.chessprogramming.org/Pawn_Hash_Table
+pub struct PawnCache {
+    entries: Vec<(u64, [[S; 2]; 3])>,
+}
+
+impl PawnCache {
+    const ENTRIES: usize = 4096;
+
+    pub fn new() -> Self {
+        // the empty key belongs to positions without pawns, which score nothing
+        PawnCache {
+            entries: vec![(0, [[(0, 0); 2]; 3]); Self::ENTRIES],
+        }
+    }
+}
+
+impl Default for PawnCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const FILE_A: u64 = 0x0101_0101_0101_0101;
+
 #[inline]
-pub fn fast_eval_endgame(board: &Chess) -> i32 {
-    let mut score = 0;
-    for (sq, p) in board.board().pieces() {
-        score += MATERIAL_ENDGAME[p.role as usize - 1] * (p.color as i32 * 2 - 1);
-        score += PSQ[p.role as usize - 1 + 6][if p.color == Color::White {
+fn file_mask(file: usize) -> u64 {
+    FILE_A << file
+}
+
+#[inline]
+fn adjacent_files(file: usize) -> u64 {
+    (if file > 0 { file_mask(file - 1) } else { 0 })
+        | (if file < 7 { file_mask(file + 1) } else { 0 })
+}
+
+/// Every square on the ranks in front of `rank` as seen by `color`
+#[inline]
+fn forward_ranks(color: Color, rank: usize) -> u64 {
+    match color {
+        Color::White if rank >= 7 => 0,
+        Color::White => !0 << (8 * (rank + 1)),
+        Color::Black => (1 << (8 * rank)) - 1,
+    }
+}
+
+fn evaluate_pawns(board: &Chess, e: &mut Evaluation) {
+    let b = board.board();
+    for &color in [Color::White, Color::Black].iter() {
+        let ours = (b.pawns() & b.by_color(color)).0;
+        let theirs = (b.pawns() & b.by_color(!color)).0;
+
+        for sq in Bitboard(ours) {
+            let (file, rank) = (sq as usize & 7, sq as usize >> 3);
+            let ahead = forward_ranks(color, rank);
+            // the rear pawn of a doubled pair is blocked by the front one
+            let front = ahead & (file_mask(file) | adjacent_files(file));
+            if theirs & front == 0 && ours & ahead & file_mask(file) == 0 {
+                let relative = if color == Color::White {
+                    rank
+                } else {
+                    7 - rank
+                };
+                e.add(Term::PassedPawns, color, PASSED_PAWN_PARAMS + relative, 1);
+            }
+            if ours & adjacent_files(file) == 0 {
+                e.add(Term::IsolatedPawns, color, ISOLATED_PAWN_PARAM, 1);
+            }
+        }
+
+        for file in 0..8 {
+            let count = (ours & file_mask(file)).count_ones() as i32;
+            if count > 1 {
+                e.add(Term::DoubledPawns, color, DOUBLED_PAWN_PARAM, count - 1);
+            }
+        }
+    }
+}
+
+fn evaluate_pieces(board: &Chess, e: &mut Evaluation) {
+    let b = board.board();
+    for (sq, p) in b.pieces() {
+        let role = p.role as usize - 1;
+        let sq = if p.color == Color::White {
             sq as usize
         } else {
             sq as usize ^ 56
-        }] * (p.color as i32 * 2 - 1)
+        };
+        e.add(Term::Material, p.color, MATERIAL_PARAMS + role, 1);
+        e.add(Term::Psq, p.color, PSQ_PARAMS + role * 64 + sq, 1);
+    }
+
+    let occupied = b.occupied();
+    for &color in [Color::White, Color::Black].iter() {
+        let ours = b.by_color(color);
+        let our_pawns = b.pawns() & ours;
+        let their_pawns = b.pawns() & b.by_color(!color);
+
+        let mut pawn_attacks = Bitboard(0);
+        for sq in their_pawns {
+            pawn_attacks |= attacks::pawn_attacks(!color, sq);
+        }
+        let area = !ours & !pawn_attacks;
+        for (i, &role) in [Role::Knight, Role::Bishop, Role::Rook, Role::Queen]
+            .iter()
+            .enumerate()
+        {
+            for sq in b.by_role(role) & ours {
+                let attacks = match role {
+                    Role::Knight => attacks::knight_attacks(sq),
+                    Role::Bishop => attacks::bishop_attacks(sq, occupied),
+                    Role::Rook => attacks::rook_attacks(sq, occupied),
+                    _ => {
+                        attacks::bishop_attacks(sq, occupied) | attacks::rook_attacks(sq, occupied)
+                    }
+                };
+                e.add(
+                    Term::Mobility,
+                    color,
+                    MOBILITY_PARAMS + i,
+                    (attacks & area).count() as i32,
+                );
+            }
+        }
+
+        for sq in b.rooks() & ours {
+            let file = Bitboard(file_mask(sq as usize & 7));
+            if (file & our_pawns).is_empty() {
+                if (file & their_pawns).is_empty() {
+                    e.add(Term::RookFiles, color, ROOK_OPEN_FILE_PARAM, 1);
+                } else {
+                    e.add(Term::RookFiles, color, ROOK_SEMI_OPEN_FILE_PARAM, 1);
+                }
+            }
+        }
+
+        if (b.bishops() & ours).count() >= 2 {
+            e.add(Term::BishopPair, color, BISHOP_PAIR_PARAM, 1);
+        }
+
+        if let Some(king) = (b.kings() & ours).first() {
+            let (file, rank) = (king as usize & 7, king as usize >> 3);
+            let beyond = match color {
+                Color::White => forward_ranks(color, rank + 2),
+                Color::Black => forward_ranks(color, rank.saturating_sub(2)),
+            };
+            let zone =
+                forward_ranks(color, rank) & !beyond & (file_mask(file) | adjacent_files(file));
+            let shelter = (our_pawns & Bitboard(zone)).count() as i32;
+            if shelter > 0 {
+                e.add(Term::KingShelter, color, KING_SHELTER_PARAM, shelter);
+            }
+        }
+    }
+}
+
+/// Evaluates `board` term by term, the pawn terms come from `cache` when given
+/// along with the pawn key of `board`. Tracing fills in the coefficients and bypasses the cache
+pub fn evaluation(
+    board: &Chess,
+    cache: Option<(&mut PawnCache, u64)>,
+    tracing: bool,
+) -> Evaluation {
+    let mut e = Evaluation::new(game_phase(board), tracing);
+    evaluate_pieces(board, &mut e);
+
+    match cache {
+        Some((cache, key)) if !tracing => {
+            let entry = &mut cache.entries[key as usize & (PawnCache::ENTRIES - 1)];
+            if entry.0 != key {
+                let mut pawns = Evaluation::new(0, false);
+                evaluate_pawns(board, &mut pawns);
+                entry.0 = key;
+                for (i, &t) in PAWN_TERMS.iter().enumerate() {
+                    entry.1[i] = pawns.terms[t as usize];
+                }
+            }
+            for (i, &t) in PAWN_TERMS.iter().enumerate() {
+                e.terms[t as usize] = entry.1[i];
+            }
+        }
+        _ => evaluate_pawns(board, &mut e),
+    }
+    e
+}
+
+/// Tapered hand-crafted score for White
+pub fn evaluate_white(board: &Chess) -> i32 {
+    evaluation(board, None, false).total()
+}
+
+/// Hand-crafted evaluation for the side to move, used whenever NNUE is not
+#[inline]
+pub fn evaluate(board: &Chess) -> i32 {
+    evaluate_white(board) * (board.turn() as i32 * 2 - 1)
+}
+
+/// Same as `evaluate`, with the pawn structure cached under `pawn_key`,
+/// the key `zobrist::pawn_hash` gives for `board`
+#[inline]
+pub fn evaluate_cached(board: &Chess, pawn_key: u64, cache: &mut PawnCache) -> i32 {
+    evaluation(board, Some((cache, pawn_key)), false).total() * (board.turn() as i32 * 2 - 1)
+}
+
+/// How many times each parameter counts in the evaluation of `board` from White's side,
+/// the evaluation tapers the sums of coefficient times opening and endgame values
+pub fn trace(board: &Chess) -> Vec<(usize, i32)> {
+    evaluation(board, None, true)
+        .coefs
+        .unwrap()
+        .into_iter()
+        .enumerate()
+        .filter(|x| x.1 != 0)
+        .collect()
+}
+
+fn write_array(out: &mut String, values: &[i32], indent: &str) {
+    for row in values.chunks(8) {
+        let row: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+        out.push_str(&format!("{}{},\n", indent, row.join(", ")));
+    }
+}
+
+fn write_pairs(out: &mut String, name: &str, values: &[S]) {
+    let pairs: Vec<String> = values
+        .iter()
+        .map(|x| format!("    ({}, {}),\n", x.0, x.1))
+        .collect();
+    out.push_str(&format!(
+        "pub const {}: [S; {}] = [\n{}];\n",
+        name,
+        values.len(),
+        pairs.concat()
+    ));
+}
+
+/// Rust source of the parameter constants of this file holding `params`
+pub fn params_source(params: &[S]) -> String {
+    let mut out = String::new();
+    for (name, opening) in [("MATERIAL_OPENING", true), ("MATERIAL_ENDGAME", false)].iter() {
+        let values: Vec<i32> = params[MATERIAL_PARAMS..PSQ_PARAMS]
+            .iter()
+            .map(|x| if *opening { x.0 } else { x.1 })
+            .collect();
+        out.push_str(&format!("pub const {}: [i32; 6] = [\n", name));
+        write_array(&mut out, &values, "    ");
+        out.push_str("];\n");
+    }
+
+    out.push_str("\n/// https://www.chessprogramming.org/PeSTO%27s_Evaluation_Function\n");
+    out.push_str("pub const PSQ: [[i32; 64]; 12] = [\n");
+    for opening in [true, false].iter() {
+        for role in 0..6 {
+            let values: Vec<i32> = params[PSQ_PARAMS + role * 64..PSQ_PARAMS + (role + 1) * 64]
+                .iter()
+                .map(|x| if *opening { x.0 } else { x.1 })
+                .collect();
+            out.push_str("    [\n");
+            write_array(&mut out, &values, "        ");
+            out.push_str("    ],\n");
+        }
+    }
+    out.push_str("];\n\n");
+
+    write_pairs(
+        &mut out,
+        "PASSED_PAWN",
+        &params[PASSED_PAWN_PARAMS..DOUBLED_PAWN_PARAM],
+    );
+    write_pairs(
+        &mut out,
+        "MOBILITY",
+        &params[MOBILITY_PARAMS..ROOK_OPEN_FILE_PARAM],
+    );
+    for (name, i) in [
+        ("DOUBLED_PAWN", DOUBLED_PAWN_PARAM),
+        ("ISOLATED_PAWN", ISOLATED_PAWN_PARAM),
+        ("ROOK_OPEN_FILE", ROOK_OPEN_FILE_PARAM),
+        ("ROOK_SEMI_OPEN_FILE", ROOK_SEMI_OPEN_FILE_PARAM),
+        ("BISHOP_PAIR", BISHOP_PAIR_PARAM),
+        ("KING_SHELTER", KING_SHELTER_PARAM),
+    ]
+    .iter()
+    {
+        out.push_str(&format!(
+            "pub const {}: S = ({}, {});\n",
+            name, params[*i].0, params[*i].1
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::{CastlingMode, FromSetup};
+    use crate::zobrist;
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    /// Swaps the colors and flips the board vertically
+    fn mirror(fen: &str) -> String {
+        let fields: Vec<&str> = fen.split(' ').collect();
+        let swap_case = |s: &str| -> String {
+            s.chars()
+                .map(|c| {
+                    if c.is_ascii_uppercase() {
+                        c.to_ascii_lowercase()
+                    } else {
+                        c.to_ascii_uppercase()
+                    }
+                })
+                .collect()
+        };
+        let board: Vec<String> = fields[0].split('/').rev().map(swap_case).collect();
+        let turn = if fields[1] == "w" { "b" } else { "w" };
+        let castling = if fields[2] == "-" {
+            "-".to_string()
+        } else {
+            let mut c: Vec<char> = swap_case(fields[2]).chars().collect();
+            c.sort_by_key(|x| x.is_ascii_lowercase());
+            c.into_iter().collect()
+        };
+        let ep = fields[3]
+            .replace('3', "x")
+            .replace('6', "3")
+            .replace('x', "6");
+        format!(
+            "{} {} {} {} {} {}",
+            board.join("/"),
+            turn,
+            castling,
+            ep,
+            fields[4],
+            fields[5]
+        )
+    }
+
+    #[test]
+    fn trace_matches_eval() {
+        let board = from_fen("2r1k3/1p3pp1/8/8/3N4/8/PPP5/RN2K3 w - - 0 1");
+        let params = params();
+        let trace = trace(&board);
+        let opening: i32 = trace.iter().map(|(i, c)| c * params[*i].0).sum();
+        let endgame: i32 = trace.iter().map(|(i, c)| c * params[*i].1).sum();
+
+        assert_eq!(game_phase(&board), 6);
+        assert_eq!(game_phase(&Chess::default()), MAX_PHASE);
+        assert_eq!(taper(opening, endgame, 6), evaluate_white(&board));
+        assert_eq!(evaluate(&board), evaluate_white(&board));
+    }
+
+    #[test]
+    fn tapered() {
+        assert_eq!(taper(100, 300, MAX_PHASE), 100);
+        assert_eq!(taper(100, 300, 0), 300);
+        assert_eq!(taper(100, 300, MAX_PHASE / 2), 200);
+        assert_eq!(taper(-100, -300, 5), -taper(100, 300, 5));
+        assert_eq!(evaluate(&Chess::default()), 0);
+    }
+
+    #[test]
+    fn symmetry() {
+        let fens = [
+            "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5Q2/PPPP1PPP/RNB1K1NR w KQkq - 0 1",
+            "2r1k3/1p3pp1/8/8/3N4/8/PPP5/RN2K3 w - - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
+        ];
+        for fen in fens.iter() {
+            let board = from_fen(fen);
+            let mirrored = from_fen(&mirror(fen));
+            assert_eq!(
+                evaluate_white(&board),
+                -evaluate_white(&mirrored),
+                "{}",
+                fen
+            );
+            assert_eq!(evaluate(&board), evaluate(&mirrored), "{}", fen);
+            assert_eq!(game_phase(&board), game_phase(&mirrored));
+        }
+    }
+
+    #[test]
+    fn pawn_structure() {
+        // doubled and isolated pawns on the a-file, passed pawns on d5 and a3
+        let e = evaluation(
+            &from_fen("4k3/5pp1/8/3P4/8/P7/P7/4K3 w - - 0 1"),
+            None,
+            false,
+        );
+        assert_eq!(e.terms[Term::DoubledPawns as usize][1], DOUBLED_PAWN);
+        let isolated = ISOLATED_PAWN;
+        assert_eq!(
+            e.terms[Term::IsolatedPawns as usize][1],
+            (isolated.0 * 3, isolated.1 * 3)
+        );
+        assert_eq!(e.terms[Term::IsolatedPawns as usize][0], (0, 0));
+        let passed = e.terms[Term::PassedPawns as usize][1];
+        let expected = (
+            PASSED_PAWN[4].0 + PASSED_PAWN[2].0,
+            PASSED_PAWN[4].1 + PASSED_PAWN[2].1,
+        );
+        assert_eq!(passed, expected);
+        // nothing stands in front of f7 and g7 either
+        let black_passed = PASSED_PAWN[1];
+        assert_eq!(
+            e.terms[Term::PassedPawns as usize][0],
+            (black_passed.0 * 2, black_passed.1 * 2)
+        );
+    }
+
+    #[test]
+    fn pieces() {
+        let e = evaluation(&from_fen("4k3/p7/8/8/8/8/8/R1B1KB2 w - - 0 1"), None, true);
+        assert_eq!(e.terms[Term::BishopPair as usize], [(0, 0), BISHOP_PAIR]);
+        assert_eq!(
+            e.terms[Term::RookFiles as usize],
+            [(0, 0), ROOK_SEMI_OPEN_FILE]
+        );
+        let coefs = e.coefs.unwrap();
+        // the rook sees a2 to a7 and b1, each bishop seven squares
+        assert_eq!(coefs[MOBILITY_PARAMS + 2], 7);
+        assert_eq!(coefs[MOBILITY_PARAMS + 1], 14);
+
+        let sheltered = evaluation(
+            &from_fen("6k1/5ppp/8/8/8/8/5PPP/6K1 w - - 0 1"),
+            None,
+            false,
+        );
+        let shelter = (KING_SHELTER.0 * 3, KING_SHELTER.1 * 3);
+        assert_eq!(
+            sheltered.terms[Term::KingShelter as usize],
+            [shelter, shelter]
+        );
+        assert_eq!(sheltered.total(), 0);
+    }
+
+    #[test]
+    fn pawn_cache() {
+        let mut cache = PawnCache::new();
+        for fen in [
+            "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5Q2/PPPP1PPP/RNB1K1NR w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 0 1",
+            "4k3/8/8/8/8/8/8/R3K3 w - - 0 1",
+        ]
+        .iter()
+        {
+            let board = from_fen(fen);
+            let key = zobrist::pawn_hash(&board);
+            for _ in 0..2 {
+                assert_eq!(evaluate_cached(&board, key, &mut cache), evaluate(&board));
+            }
+            assert_eq!(
+                evaluation(&board, Some((&mut cache, key)), false),
+                evaluation(&board, None, false)
+            );
+        }
+    }
+
+    #[test]
+    fn display() {
+        let e = evaluation(&Chess::default(), None, false);
+        let table = e.to_string();
+        for t in TERMS.iter() {
+            assert!(table.contains(t.name()));
+        }
+        assert!(table.ends_with("Phase 24/24, total 0 for White"));
+    }
+
+    #[test]
+    fn source_of_params() {
+        let source = params_source(&params());
+        assert!(source.contains("pub const MATERIAL_OPENING: [i32; 6] = [\n    110, 450, 470,"));
+        assert!(source.contains("pub const PSQ: [[i32; 64]; 12] = [\n    [\n        "));
+        assert_eq!(source.matches("    ],\n").count(), 12);
+        assert!(source.contains("pub const PASSED_PAWN: [S; 8] = [\n    (0, 0),\n"));
+        assert!(source.contains("pub const BISHOP_PAIR: S = (30, 50);\n"));
     }
-    score * (board.turn() as i32 * 2 - 1) * 3
 }
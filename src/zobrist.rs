@@ -0,0 +1,253 @@
+use lazy_static::lazy_static;
+
+use crate::chess::{Bitboard, Chess, Color, Move, Role, Setup, Square};
+
+/// https://www.chessprogramming.org/Zobrist_Hashing
+pub struct ZobristKeys {
+    pub pieces: [[[u64; 64]; 6]; 2],
+    pub castling: [u64; 64],
+    pub ep_file: [u64; 8],
+    pub side: u64,
+}
+
+/// Xorshift with a fixed seed of its own, so that the keys are the same
+/// in every run and hash files written by one run can be read by the next
+struct KeyGenerator(u64);
+
+impl KeyGenerator {
+    fn next_key(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+fn init_keys() -> ZobristKeys {
+    let mut rng = KeyGenerator(1493682623);
+    let mut keys = ZobristKeys {
+        pieces: [[[0; 64]; 6]; 2],
+        castling: [0; 64],
+        ep_file: [0; 8],
+        side: 0,
+    };
+    for color in keys.pieces.iter_mut() {
+        for role in color.iter_mut() {
+            for sq in role.iter_mut() {
+                *sq = rng.next_key();
+            }
+        }
+    }
+    // castling rights are keyed by the squares of the rooks that may still castle
+    for sq in keys.castling.iter_mut() {
+        *sq = rng.next_key();
+    }
+    for file in keys.ep_file.iter_mut() {
+        *file = rng.next_key();
+    }
+    keys.side = rng.next_key();
+    keys
+}
+
+lazy_static! {
+    pub static ref ZOBRIST: ZobristKeys = init_keys();
+}
+
+#[inline(always)]
+fn piece_key(color: Color, role: Role, sq: usize) -> u64 {
+    ZOBRIST.pieces[color as usize][role as usize - 1][sq]
+}
+
+#[inline(always)]
+fn castling_key(rights: Bitboard) -> u64 {
+    rights
+        .into_iter()
+        .fold(0, |h, sq| h ^ ZOBRIST.castling[sq as usize])
+}
+
+#[inline(always)]
+fn ep_key(ep_square: Option<Square>) -> u64 {
+    ep_square.map_or(0, |sq| ZOBRIST.ep_file[sq as usize & 7])
+}
+
+/// Computes the key of a position from scratch
+pub fn hash(board: &Chess) -> u64 {
+    let mut h = 0;
+    for (sq, p) in board.board().pieces() {
+        h ^= piece_key(p.color, p.role, sq as usize);
+    }
+    if board.turn() == Color::Black {
+        h ^= ZOBRIST.side;
+    }
+    h ^ castling_key(board.castling_rights()) ^ ep_key(board.ep_square())
+}
+
+/// Key of the pawns alone, for the pawn structure cache of the evaluation
+pub fn pawn_hash(board: &Chess) -> u64 {
+    let b = board.board();
+    b.pawns().into_iter().fold(0, |h, sq| {
+        h ^ piece_key(b.color_at(sq).unwrap(), Role::Pawn, sq as usize)
+    })
+}
+
+/// Incrementally computes the key of `after`, reached by playing `m` on `board`
+pub fn update(hash: u64, board: &Chess, m: &Move, after: &Chess) -> u64 {
+    let us = board.turn();
+    let mut h = hash ^ ZOBRIST.side;
+
+    match *m {
+        Move::Normal {
+            role,
+            from,
+            capture,
+            to,
+            promotion,
+        } => {
+            h ^= piece_key(us, role, from as usize);
+            h ^= piece_key(us, promotion.unwrap_or(role), to as usize);
+            if let Some(captured) = capture {
+                h ^= piece_key(!us, captured, to as usize);
+            }
+        }
+        Move::EnPassant { from, to } => {
+            let captured = (to as usize & 7) | (from as usize & 56);
+            h ^= piece_key(us, Role::Pawn, from as usize);
+            h ^= piece_key(us, Role::Pawn, to as usize);
+            h ^= piece_key(!us, Role::Pawn, captured);
+        }
+        Move::Castle { king, rook } => {
+            let base = king as usize & 56;
+            let (king_to, rook_to) = if rook as usize > king as usize {
+                (base + 6, base + 5)
+            } else {
+                (base + 2, base + 3)
+            };
+            h ^= piece_key(us, Role::King, king as usize);
+            h ^= piece_key(us, Role::King, king_to);
+            h ^= piece_key(us, Role::Rook, rook as usize);
+            h ^= piece_key(us, Role::Rook, rook_to);
+        }
+        Move::Put { .. } => unreachable!(),
+    }
+
+    h ^= castling_key(board.castling_rights()) ^ castling_key(after.castling_rights());
+    h ^ ep_key(board.ep_square()) ^ ep_key(after.ep_square())
+}
+
+/// Incrementally computes the pawn key after playing `m` on `board`
+pub fn update_pawns(hash: u64, board: &Chess, m: &Move) -> u64 {
+    let us = board.turn();
+    let mut h = hash;
+
+    match *m {
+        Move::Normal {
+            role,
+            from,
+            capture,
+            to,
+            promotion,
+        } => {
+            if role == Role::Pawn {
+                h ^= piece_key(us, Role::Pawn, from as usize);
+                if promotion.is_none() {
+                    h ^= piece_key(us, Role::Pawn, to as usize);
+                }
+            }
+            if capture == Some(Role::Pawn) {
+                h ^= piece_key(!us, Role::Pawn, to as usize);
+            }
+        }
+        Move::EnPassant { from, to } => {
+            let captured = (to as usize & 7) | (from as usize & 56);
+            h ^= piece_key(us, Role::Pawn, from as usize);
+            h ^= piece_key(us, Role::Pawn, to as usize);
+            h ^= piece_key(!us, Role::Pawn, captured);
+        }
+        Move::Castle { .. } => {}
+        Move::Put { .. } => unreachable!(),
+    }
+    h
+}
+
+/// Key after passing the turn, `after` being `board` with the turn swapped
+#[inline]
+pub fn null_move(hash: u64, board: &Chess, after: &Chess) -> u64 {
+    hash ^ ZOBRIST.side ^ ep_key(board.ep_square()) ^ ep_key(after.ep_square())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chess::fen::Fen;
+    use crate::chess::uci::Uci;
+    use crate::chess::{CastlingMode, FromSetup, Position};
+
+    fn from_fen(fen: &str) -> Chess {
+        Chess::from_setup(&Fen::from_str(fen).unwrap(), CastlingMode::Standard).unwrap()
+    }
+
+    #[test]
+    fn fixed_keys() {
+        // the first and the last key drawn, whatever ran before
+        assert_eq!(ZOBRIST.pieces[0][0][0], 0x0001_6f34_11af_4d47);
+        assert_eq!(ZOBRIST.side, 0xd132_e7b9_4885_05ba);
+        assert_eq!(init_keys().side, ZOBRIST.side);
+    }
+
+    #[test]
+    fn turn_changes_key() {
+        assert_ne!(
+            hash(&from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1")),
+            hash(&from_fen("4k3/8/8/8/8/8/8/4K3 b - - 0 1"))
+        );
+    }
+
+    #[test]
+    fn castling_changes_key() {
+        let all = hash(&from_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1"));
+        let some = hash(&from_fen("r3k2r/8/8/8/8/8/8/R3K2R w Kq - 0 1"));
+        let none = hash(&from_fen("r3k2r/8/8/8/8/8/8/R3K2R w - - 0 1"));
+        assert_ne!(all, some);
+        assert_ne!(all, none);
+        assert_ne!(some, none);
+    }
+
+    #[test]
+    fn ep_changes_key() {
+        assert_ne!(
+            hash(&from_fen(
+                "rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 3"
+            )),
+            hash(&from_fen(
+                "rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 3"
+            ))
+        );
+    }
+
+    #[test]
+    fn incremental_matches_full() {
+        // en passant, promotion with capture, castling on both sides
+        let moves = [
+            "e2e4", "d7d5", "e4e5", "f7f5", "e5f6", "g8h6", "f6g7", "b8c6", "g7h8q", "c8d7",
+            "g1f3", "e7e6", "f1e2", "d8e7", "e1g1", "e8c8",
+        ];
+        let mut board = Chess::default();
+        let mut h = hash(&board);
+        let mut pawns = pawn_hash(&board);
+        for m in moves.iter() {
+            let m = Uci::from_str(m).unwrap().to_move(&board).unwrap();
+            let mut nb = board.clone();
+            nb.play_unchecked(&m);
+            h = update(h, &board, &m, &nb);
+            assert_eq!(h, hash(&nb));
+            pawns = update_pawns(pawns, &board, &m);
+            assert_eq!(pawns, pawn_hash(&nb));
+            board = nb;
+        }
+
+        let nb = board.clone().swap_turn().unwrap();
+        assert_eq!(null_move(h, &board, &nb), hash(&nb));
+    }
+}